@@ -0,0 +1,256 @@
+use crate::mapper::Mapper;
+
+/// Anything the CPU can be wired to. Reads take `&mut self` because reading some
+/// addresses has side effects on real hardware (PPU status, controller shift registers).
+pub trait Mem {
+    fn mem_read(&mut self, addr: u16) -> u8;
+
+    fn mem_write(&mut self, addr: u16, data: u8);
+
+    /// What a read of `addr` would return, without any of its side effects. For debuggers
+    /// and tracers, which must not disturb the hardware they look at.
+    fn mem_peek(&self, addr: u16) -> u8;
+
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
+        let lo = self.mem_read(pos) as u16;
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
+    fn mem_write_u16(&mut self, pos: u16, data: u16) {
+        let hi = (data >> 8) as u8;
+        let lo = (data & 0xFF) as u8;
+        self.mem_write(pos, lo);
+        self.mem_write(pos.wrapping_add(1), hi);
+    }
+}
+
+/// A flat 64K of RAM with nothing mapped, for unit tests and plain 6502 programs.
+#[derive(Debug)]
+pub struct Ram {
+    memory: [u8; 0x10000],
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ram {
+    pub fn new() -> Self {
+        Ram {
+            memory: [0; 0x10000],
+        }
+    }
+}
+
+impl Mem for Ram {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.memory[addr as usize] = data;
+    }
+
+    fn mem_peek(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+}
+
+//  _______________ $10000  _______________
+// | PRG-ROM       |       |               |
+// | Upper Bank    |       |               |
+// |_ _ _ _ _ _ _ _| $C000 | PRG-ROM       |
+// | PRG-ROM       |       |               |
+// | Lower Bank    |       |               |
+// |_______________| $8000 |_______________|
+// | SRAM          |       | SRAM          |
+// |_______________| $6000 |_______________|
+// | Expansion ROM |       | Expansion ROM |
+// |_______________| $4020 |_______________|
+// | I/O Registers |       |               |
+// |_ _ _ _ _ _ _ _| $4000 |               |
+// | Mirrors       |       | I/O Registers |
+// | $2000-$2007   |       |               |
+// |_ _ _ _ _ _ _ _| $2008 |               |
+// | I/O Registers |       |               |
+// |_______________| $2000 |_______________|
+// | Mirrors       |       |               |
+// | $0000-$07FF   |       |               |
+// |_ _ _ _ _ _ _ _| $0800 |               |
+// | RAM           |       | RAM           |
+// |_ _ _ _ _ _ _ _| $0200 |               |
+// | Stack         |       |               |
+// |_ _ _ _ _ _ _ _| $0100 |               |
+// | Zero Page     |       |               |
+// |_______________| $0000 |_______________|
+
+const RAM: u16 = 0x0000;
+const RAM_MIRRORS_END: u16 = 0x1FFF;
+const PPU_REGISTERS: u16 = 0x2000;
+const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const APU_IO_REGISTERS: u16 = 0x4000;
+const APU_IO_REGISTERS_END: u16 = 0x401F;
+const EXPANSION_ROM: u16 = 0x4020;
+const PRG_ROM_END: u16 = 0xFFFF;
+
+/// The NES CPU address space. $4020-$FFFF belongs to the cartridge's [`Mapper`].
+///
+/// Nothing is attached to the PPU and APU/IO registers yet, so reading them (and any other
+/// address nothing drives) returns the open-bus value: whatever was last on the data bus.
+#[derive(Debug)]
+pub struct Bus {
+    cpu_vram: [u8; 0x800],
+    mapper: Box<dyn Mapper>,
+    open_bus: u8,
+}
+
+impl Bus {
+    pub fn new(mapper: Box<dyn Mapper>) -> Self {
+        Bus {
+            cpu_vram: [0; 0x800],
+            mapper,
+            open_bus: 0,
+        }
+    }
+
+    /// The cartridge, e.g. to persist its battery-backed RAM.
+    #[allow(dead_code)] // for the frontend, which doesn't save games yet
+    pub fn mapper(&self) -> &dyn Mapper {
+        self.mapper.as_ref()
+    }
+
+    #[allow(dead_code)] // for the frontend, which doesn't load saves yet
+    pub fn mapper_mut(&mut self) -> &mut dyn Mapper {
+        self.mapper.as_mut()
+    }
+}
+
+impl Mem for Bus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.mapper.cpu_clock();
+        let data = match addr {
+            RAM..=RAM_MIRRORS_END => Some(self.cpu_vram[(addr & 0b0000_0111_1111_1111) as usize]),
+            // no PPU attached yet
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => None,
+            APU_IO_REGISTERS..=APU_IO_REGISTERS_END => None,
+            EXPANSION_ROM..=PRG_ROM_END => self.mapper.cpu_read(addr),
+        };
+
+        if let Some(data) = data {
+            self.open_bus = data;
+        }
+        self.open_bus
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.mapper.cpu_clock();
+        self.open_bus = data;
+
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b0000_0111_1111_1111) as usize] = data,
+            // no PPU attached yet
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {}
+            APU_IO_REGISTERS..=APU_IO_REGISTERS_END => {}
+            EXPANSION_ROM..=PRG_ROM_END => self.mapper.cpu_write(addr, data),
+        }
+    }
+
+    fn mem_peek(&self, addr: u16) -> u8 {
+        let data = match addr {
+            RAM..=RAM_MIRRORS_END => Some(self.cpu_vram[(addr & 0b0000_0111_1111_1111) as usize]),
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => None,
+            APU_IO_REGISTERS..=APU_IO_REGISTERS_END => None,
+            EXPANSION_ROM..=PRG_ROM_END => self.mapper.cpu_peek(addr),
+        };
+        data.unwrap_or(self.open_bus)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::mapper::{Mmc1, Nrom};
+
+    fn test_bus(prg_rom: Vec<u8>) -> Bus {
+        Bus::new(Box::new(Nrom::new(test_rom(prg_rom))))
+    }
+
+    #[test]
+    fn ram_u16_is_little_endian() {
+        let mut ram = Ram::new();
+        ram.mem_write_u16(0x1234, 0xBEEF);
+
+        assert_eq!(ram.mem_read(0x1234), 0xEF);
+        assert_eq!(ram.mem_read(0x1235), 0xBE);
+        assert_eq!(ram.mem_read_u16(0x1234), 0xBEEF);
+    }
+
+    #[test]
+    fn ram_is_mirrored_through_1fff() {
+        let mut bus = test_bus(vec![0; 0x4000]);
+        bus.mem_write(0x0001, 0x42);
+
+        assert_eq!(bus.mem_read(0x0801), 0x42);
+        assert_eq!(bus.mem_read(0x1001), 0x42);
+        assert_eq!(bus.mem_read(0x1801), 0x42);
+
+        bus.mem_write(0x1FFF, 0x17);
+        assert_eq!(bus.mem_read(0x07FF), 0x17);
+    }
+
+    #[test]
+    fn unmapped_reads_return_open_bus() {
+        let mut bus = test_bus(vec![0; 0x4000]);
+        bus.mem_write(0x0010, 0x5A);
+        assert_eq!(bus.mem_read(0x0010), 0x5A);
+
+        assert_eq!(bus.mem_read(0x2002), 0x5A);
+        assert_eq!(bus.mem_read(0x4016), 0x5A);
+        assert_eq!(bus.mem_read(0x5000), 0x5A);
+    }
+
+    #[test]
+    fn peeks_leave_open_bus_alone() {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0] = 0xAA;
+        let mut bus = test_bus(prg_rom);
+        bus.mem_write(0x0010, 0x5A);
+
+        assert_eq!(bus.mem_peek(0x8000), 0xAA);
+        assert_eq!(bus.mem_peek(0x0010), 0x5A);
+        assert_eq!(bus.mem_peek(0x2002), 0x5A);
+        assert_eq!(bus.mem_read(0x4016), 0x5A);
+    }
+
+    #[test]
+    fn battery_ram_through_the_mapper() {
+        let mut rom = test_rom(vec![0; 0x8000]);
+        rom.battery = true;
+        rom.prg_ram_size = 0;
+        rom.prg_nvram_size = 0x2000;
+        let mut bus = Bus::new(Box::new(Mmc1::new(rom)));
+
+        bus.mapper_mut().load_save_ram(&[1, 2]);
+        assert_eq!(bus.mem_read(0x6001), 2);
+
+        bus.mem_write(0x6002, 3);
+        assert_eq!(&bus.mapper().save_ram().unwrap()[..3], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn cartridge_space_goes_to_the_mapper() {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0] = 0xAA;
+        let mut bus = test_bus(prg_rom);
+
+        assert_eq!(bus.mem_read(0x8000), 0xAA);
+        assert_eq!(bus.mem_read(0xC000), 0xAA);
+
+        bus.mem_write(0x6000, 0x01);
+        assert_eq!(bus.mem_read(0x6000), 0x01);
+    }
+}
@@ -0,0 +1,500 @@
+#![allow(dead_code)]
+
+use std::fmt;
+
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_PAGE_SIZE: usize = 16384;
+const CHR_ROM_PAGE_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Vertical,
+    Horizontal,
+    FourScreen,
+    /// Every nametable shows the first 1KB of VRAM, chosen at run time by some mappers.
+    SingleScreenLower,
+    /// Every nametable shows the second 1KB of VRAM.
+    SingleScreenUpper,
+}
+
+/// The console the cartridge was made for (NES 2.0 byte 7, bits 0-1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    /// One of the extended console types in the low nybble of byte 13.
+    Extended(u8),
+}
+
+/// The CPU/PPU timing the cartridge expects (NES 2.0 byte 12, bits 0-1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    /// Works on either; we run it as NTSC.
+    MultiRegion,
+    Dendy,
+}
+
+impl Timing {
+    pub fn cpu_clock_hz(self) -> u32 {
+        match self {
+            Timing::Ntsc | Timing::MultiRegion => 1_789_773,
+            Timing::Pal => 1_662_607,
+            Timing::Dendy => 1_773_448,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RomError {
+    /// The file doesn't start with "NES\x1A".
+    NotINes,
+    /// The header declares more data than the file holds.
+    Truncated {
+        expected: usize,
+        actual: usize,
+    },
+    /// The header declares no PRG-ROM, so there is nothing to run.
+    NoPrgRom,
+    UnsupportedMapper(u16),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::NotINes => write!(f, "file is not in iNES file format"),
+            RomError::Truncated { expected, actual } => write!(
+                f,
+                "file is {} bytes but the header describes {}",
+                actual, expected
+            ),
+            RomError::NoPrgRom => write!(f, "header declares no PRG-ROM"),
+            RomError::UnsupportedMapper(mapper) => write!(f, "mapper {} is not supported", mapper),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+/// A cartridge image parsed from an iNES (.nes) file.
+///
+/// ```text
+/// 0-3   "NES" followed by MS-DOS end-of-file ($1A)
+/// 4     PRG-ROM size in 16KB units
+/// 5     CHR-ROM size in 8KB units (0 means the board has CHR-RAM)
+/// 6     76543210
+///       ||||||||
+///       |||||||+- Mirroring: 0 horizontal, 1 vertical
+///       ||||||+-- Battery-backed PRG-RAM at $6000-$7FFF
+///       |||||+--- 512-byte trainer at $7000-$71FF
+///       ||||+---- Ignore the mirroring bit, provide four-screen VRAM
+///       ++++----- Lower nybble of mapper number
+/// 7     76543210
+///       ||||||||
+///       ||||||++- Console type (NES 2.0)
+///       ||||++--- 10 means the rest of the header is in NES 2.0 format
+///       ++++----- Upper nybble of mapper number
+/// 8-15  Rarely used, should be zero
+/// ```
+///
+/// NES 2.0 uses the rest of the header:
+///
+/// ```text
+/// 8     SSSSMMMM  Submapper, mapper bits 8-11
+/// 9     CCCCPPPP  CHR-ROM and PRG-ROM size MSBs; $F selects exponent-multiplier notation
+/// 10    NNNNRRRR  PRG-NVRAM and PRG-RAM shift counts: 64 << shift bytes, 0 for none
+/// 11    NNNNRRRR  CHR-NVRAM and CHR-RAM shift counts
+/// 12    ......TT  Timing: NTSC, PAL, multi-region, Dendy
+/// 13    ....EEEE  Extended console type, when byte 7 says so
+/// ```
+#[derive(Debug, Clone)]
+pub struct Rom {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub trainer: Option<Vec<u8>>,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub screen_mirroring: Mirroring,
+    pub battery: bool,
+    pub nes2: bool,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+}
+
+/// A NES 2.0 ROM size: `lsb` units of `unit` bytes with `msb` on top, or when `msb` is $F,
+/// `lsb` is EEEEEEMM for 2^E * (MM * 2 + 1) bytes.
+fn rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+    if msb == 0x0F {
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        1usize
+            .checked_shl((lsb >> 2) as u32)
+            .and_then(|size| size.checked_mul(multiplier))
+            .unwrap_or(usize::MAX)
+    } else {
+        (((msb as usize) << 8) | lsb as usize) * unit
+    }
+}
+
+/// A NES 2.0 RAM size from its shift count.
+fn ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+impl Rom {
+    pub fn new(raw: &[u8]) -> Result<Rom, RomError> {
+        if raw.len() < HEADER_SIZE || raw[0..4] != NES_TAG {
+            return Err(RomError::NotINes);
+        }
+
+        let version = (raw[7] >> 2) & 0b11;
+        let nes2 = version == 0b10;
+        // any other version, or an iNES header with junk in the padding at bytes 12-15, is
+        // an archaic header, often with a ripper's signature ("DiskDude!") across bytes 7-15,
+        // so only byte 6 can be trusted
+        let archaic = match version {
+            0b00 => raw[12..16].iter().any(|&b| b != 0),
+            0b10 => false,
+            _ => true,
+        };
+
+        let mapper_high = if archaic { 0 } else { raw[7] & 0b1111_0000 };
+        let mut mapper = (mapper_high | (raw[6] >> 4)) as u16;
+
+        let four_screen = raw[6] & 0b1000 != 0;
+        let vertical_mirroring = raw[6] & 0b1 != 0;
+        let screen_mirroring = match (four_screen, vertical_mirroring) {
+            (true, _) => Mirroring::FourScreen,
+            (false, true) => Mirroring::Vertical,
+            (false, false) => Mirroring::Horizontal,
+        };
+        let battery = raw[6] & 0b10 != 0;
+        let has_trainer = raw[6] & 0b100 != 0;
+
+        let mut submapper = 0;
+        let mut timing = Timing::Ntsc;
+        let mut console_type = ConsoleType::Nes;
+        let prg_rom_size;
+        let chr_rom_size;
+        let (prg_ram_size, prg_nvram_size, chr_ram_size, chr_nvram_size);
+
+        if nes2 {
+            mapper |= ((raw[8] & 0x0F) as u16) << 8;
+            submapper = raw[8] >> 4;
+            prg_rom_size = rom_size(raw[4], raw[9] & 0x0F, PRG_ROM_PAGE_SIZE);
+            chr_rom_size = rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE);
+            prg_ram_size = ram_size(raw[10] & 0x0F);
+            prg_nvram_size = ram_size(raw[10] >> 4);
+            chr_ram_size = ram_size(raw[11] & 0x0F);
+            chr_nvram_size = ram_size(raw[11] >> 4);
+            timing = match raw[12] & 0b11 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultiRegion,
+                _ => Timing::Dendy,
+            };
+            console_type = match raw[7] & 0b11 {
+                0 => ConsoleType::Nes,
+                1 => ConsoleType::VsSystem,
+                2 => ConsoleType::Playchoice10,
+                _ => ConsoleType::Extended(raw[13] & 0x0F),
+            };
+        } else {
+            prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+            chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+            // iNES can't say, so assume the usual 8KB at $6000, battery-backed if flagged,
+            // and 8KB of CHR-RAM when there's no CHR-ROM
+            (prg_ram_size, prg_nvram_size) = if battery { (0, 0x2000) } else { (0x2000, 0) };
+            chr_ram_size = if chr_rom_size == 0 { 0x2000 } else { 0 };
+            chr_nvram_size = 0;
+        }
+
+        if prg_rom_size == 0 {
+            return Err(RomError::NoPrgRom);
+        }
+
+        let trainer_size = if has_trainer { TRAINER_SIZE } else { 0 };
+        let prg_rom_start = HEADER_SIZE + trainer_size;
+        let chr_rom_start = prg_rom_start.saturating_add(prg_rom_size);
+        let expected = chr_rom_start.saturating_add(chr_rom_size);
+        if raw.len() < expected {
+            return Err(RomError::Truncated {
+                expected,
+                actual: raw.len(),
+            });
+        }
+
+        Ok(Rom {
+            prg_rom: raw[prg_rom_start..chr_rom_start].to_vec(),
+            chr_rom: raw[chr_rom_start..expected].to_vec(),
+            trainer: has_trainer.then(|| raw[HEADER_SIZE..prg_rom_start].to_vec()),
+            mapper,
+            submapper,
+            screen_mirroring,
+            battery,
+            nes2,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+            chr_nvram_size,
+            timing,
+            console_type,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    pub struct TestRom {
+        pub header: Vec<u8>,
+        pub trainer: Option<Vec<u8>>,
+        pub prg_rom: Vec<u8>,
+        pub chr_rom: Vec<u8>,
+    }
+
+    pub fn header(prg_banks: u8, chr_banks: u8, flags_6: u8, flags_7: u8) -> Vec<u8> {
+        let mut header = NES_TAG.to_vec();
+        header.extend([prg_banks, chr_banks, flags_6, flags_7]);
+        header.resize(HEADER_SIZE, 0);
+        header
+    }
+
+    pub fn create_rom(rom: TestRom) -> Vec<u8> {
+        let mut result = Vec::with_capacity(
+            rom.header.len()
+                + rom.trainer.as_ref().map_or(0, |t| t.len())
+                + rom.prg_rom.len()
+                + rom.chr_rom.len(),
+        );
+
+        result.extend(&rom.header);
+        if let Some(t) = rom.trainer {
+            result.extend(t);
+        }
+        result.extend(&rom.prg_rom);
+        result.extend(&rom.chr_rom);
+
+        result
+    }
+
+    /// A mapper 0 image with the given PRG-ROM, which must be a whole number of 16KB banks.
+    pub fn test_rom(prg_rom: Vec<u8>) -> Rom {
+        let test_rom = create_rom(TestRom {
+            header: header((prg_rom.len() / PRG_ROM_PAGE_SIZE) as u8, 0x01, 0x00, 0x00),
+            trainer: None,
+            prg_rom,
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        Rom::new(&test_rom).unwrap()
+    }
+
+    #[test]
+    fn test() {
+        let test_rom = create_rom(TestRom {
+            header: header(0x02, 0x01, 0x31, 0x00),
+            trainer: None,
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec!(2; CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert!(!rom.battery);
+        assert_eq!(rom.trainer, None);
+    }
+
+    #[test]
+    fn test_with_trainer() {
+        let test_rom = create_rom(TestRom {
+            header: header(0x02, 0x01, 0x31 | 0b110, 0x00),
+            trainer: Some(vec![0; TRAINER_SIZE]),
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec!(2; CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert!(rom.battery);
+        assert_eq!(rom.trainer, Some(vec![0; TRAINER_SIZE]));
+    }
+
+    #[test]
+    fn test_four_screen_and_high_mapper_nybble() {
+        let test_rom = create_rom(TestRom {
+            header: header(0x01, 0x00, 0x19, 0x40),
+            trainer: None,
+            prg_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.mapper, 0x41);
+        assert_eq!(rom.screen_mirroring, Mirroring::FourScreen);
+        assert!(rom.chr_rom.is_empty());
+    }
+
+    #[test]
+    fn test_ines_defaults() {
+        let rom = test_rom(vec![1; PRG_ROM_PAGE_SIZE]);
+
+        assert!(!rom.nes2);
+        assert_eq!(rom.submapper, 0);
+        assert_eq!(rom.prg_ram_size, 0x2000);
+        assert_eq!(rom.prg_nvram_size, 0);
+        assert_eq!(rom.chr_ram_size, 0);
+        assert_eq!(rom.timing, Timing::Ntsc);
+        assert_eq!(rom.console_type, ConsoleType::Nes);
+    }
+
+    #[test]
+    fn test_nes2() {
+        let mut header = header(0x02, 0x00, 0x12, 0x19);
+        header[8] = 0x31; // submapper 3, mapper bits 8-11 = 1
+        header[10] = 0x70; // 8KB PRG-NVRAM, no PRG-RAM
+        header[11] = 0x07; // 8KB CHR-RAM
+        header[12] = 0x01; // PAL
+        let test_rom = create_rom(TestRom {
+            header,
+            trainer: None,
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+
+        assert!(rom.nes2);
+        assert_eq!(rom.mapper, 0x111);
+        assert_eq!(rom.submapper, 3);
+        assert!(rom.battery);
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.prg_ram_size, 0);
+        assert_eq!(rom.prg_nvram_size, 0x2000);
+        assert_eq!(rom.chr_ram_size, 0x2000);
+        assert_eq!(rom.chr_nvram_size, 0);
+        assert_eq!(rom.timing, Timing::Pal);
+        assert_eq!(rom.console_type, ConsoleType::VsSystem);
+    }
+
+    #[test]
+    fn test_nes2_rom_sizes() {
+        // MSB nybbles extend the bank counts
+        assert_eq!(
+            rom_size(0x02, 0x1, PRG_ROM_PAGE_SIZE),
+            0x102 * PRG_ROM_PAGE_SIZE
+        );
+        // exponent-multiplier: 2^4 * (1 * 2 + 1)
+        assert_eq!(rom_size(0b0001_0001, 0xF, PRG_ROM_PAGE_SIZE), 48);
+        assert_eq!(rom_size(0xFF, 0xF, PRG_ROM_PAGE_SIZE), usize::MAX);
+
+        let mut header = header(0b0000_1100, 0x00, 0x00, 0x08);
+        header[9] = 0x0F;
+        let test_rom = create_rom(TestRom {
+            header,
+            trainer: None,
+            prg_rom: vec![1; 8],
+            chr_rom: vec![],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.prg_rom.len(), 8);
+        assert_eq!(rom.chr_ram_size, 0);
+    }
+
+    #[test]
+    fn test_extended_console_type() {
+        let mut header = header(0x01, 0x01, 0x00, 0x0B);
+        header[13] = 0x03;
+        let test_rom = create_rom(TestRom {
+            header,
+            trainer: None,
+            prg_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.console_type, ConsoleType::Extended(3));
+    }
+
+    #[test]
+    fn test_archaic_ines() {
+        let mut raw_header = header(0x01, 0x01, 0x31, 0x00);
+        raw_header[7..].copy_from_slice(b"DiskDude!");
+        let test_rom = create_rom(TestRom {
+            header: raw_header,
+            trainer: None,
+            prg_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+
+        assert!(!rom.nes2);
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.submapper, 0);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert_eq!(rom.console_type, ConsoleType::Nes);
+        assert_eq!(rom.prg_rom, vec![1; PRG_ROM_PAGE_SIZE]);
+
+        // version bits that say iNES, but a signature in the padding
+        let mut raw_header = header(0x01, 0x01, 0x31, 0x10);
+        raw_header[12..].copy_from_slice(b"ude!");
+        let test_rom = create_rom(TestRom {
+            header: raw_header,
+            trainer: None,
+            prg_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        assert_eq!(Rom::new(&test_rom).unwrap().mapper, 3);
+    }
+
+    #[test]
+    fn test_malformed_headers() {
+        assert_eq!(Rom::new(&[]).unwrap_err(), RomError::NotINes);
+        assert_eq!(
+            Rom::new(b"NES\x1b\x01\x01\0\0\0\0\0\0\0\0\0\0").unwrap_err(),
+            RomError::NotINes
+        );
+        assert_eq!(
+            Rom::new(b"NES\x1a\x00\x01\0\0\0\0\0\0\0\0\0\0").unwrap_err(),
+            RomError::NoPrgRom
+        );
+
+        let truncated = create_rom(TestRom {
+            header: header(0x02, 0x01, 0x00, 0x00),
+            trainer: None,
+            prg_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+        assert_eq!(
+            Rom::new(&truncated).unwrap_err(),
+            RomError::Truncated {
+                expected: HEADER_SIZE + 2 * PRG_ROM_PAGE_SIZE + CHR_ROM_PAGE_SIZE,
+                actual: HEADER_SIZE + PRG_ROM_PAGE_SIZE,
+            }
+        );
+    }
+}
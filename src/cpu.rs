@@ -1,119 +1,619 @@
 #![allow(dead_code)]
 
+use crate::bus::{Bus, Mem, Ram};
+use crate::cartridge::{Rom, RomError};
+use crate::flags::CpuFlags;
+use crate::mapper;
+use crate::opcodes;
+use std::fmt;
+
+const PROGRAM_START: u16 = 0x8000;
+
+const STACK: u16 = 0x0100;
+const STACK_RESET: u8 = 0xFD;
+
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
+/// Cycles spent pushing state and fetching the vector when an NMI or IRQ is taken.
+const INTERRUPT_CYCLES: u64 = 7;
+
+/// What to do when the CPU fetches a byte it can't decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownOpcodePolicy {
+    /// Stop and report [`CpuError::UnknownOpcode`].
+    Error,
+    /// Skip the byte as a one-byte, two-cycle NOP.
+    Nop,
+    /// Lock up like the NMOS JAM/KIL opcodes: the CPU stops fetching until it is reset and
+    /// every run reports [`CpuError::Jammed`].
+    Jam,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CpuError {
+    UnknownOpcode {
+        opcode: u8,
+        program_counter: u16,
+    },
+    /// The CPU has halted on a JAM and won't run again until it is reset.
+    Jammed {
+        opcode: u8,
+        program_counter: u16,
+    },
+    /// The program doesn't fit between the load address and the top of memory.
+    ProgramTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// An instruction asked for an operand through a mode it has no operand for.
+    BadAddressingMode(AddressingMode),
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuError::UnknownOpcode {
+                opcode,
+                program_counter,
+            } => write!(
+                f,
+                "unknown opcode {:#04x} at {:#06x}",
+                opcode, program_counter
+            ),
+            CpuError::Jammed {
+                opcode,
+                program_counter,
+            } => write!(
+                f,
+                "CPU jammed by opcode {:#04x} at {:#06x}",
+                opcode, program_counter
+            ),
+            CpuError::ProgramTooLarge { len, max } => {
+                write!(f, "program is {} bytes, at most {} fit", len, max)
+            }
+            CpuError::BadAddressingMode(mode) => {
+                write!(f, "addressing mode {:?} has no operand address", mode)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+}
+
+/// What a single [`CPU::step`] did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    /// Where the instruction was fetched from.
+    pub address: u16,
+    pub opcode: u8,
+    /// Cycles the step took, including servicing `interrupt`.
+    pub cycles: u64,
+    /// The interrupt taken before the instruction, if any.
+    pub interrupt: Option<Interrupt>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpuConfig {
+    /// Execute the stable undocumented opcodes (LAX, SAX, DCP, ISB, SLO, RLA, SRE, RRA, ANC,
+    /// ALR, ARR, AXS, the extra NOPs and SBC $EB). Commercial NES games and test ROMs use
+    /// them; turn this off to treat them like any other undecodable byte.
+    pub unofficial_opcodes: bool,
+    pub unknown_opcodes: UnknownOpcodePolicy,
+    /// Honour the D flag in ADC and SBC (and the unofficial opcodes built on them) with the
+    /// NMOS 6502's BCD arithmetic. The 2A03 has the flag but not the circuitry, so this is
+    /// off for the NES.
+    pub decimal_mode: bool,
+}
+
+impl Default for CpuConfig {
+    fn default() -> Self {
+        CpuConfig {
+            unofficial_opcodes: true,
+            unknown_opcodes: UnknownOpcodePolicy::Error,
+            decimal_mode: false,
+        }
+    }
+}
+
+impl CpuConfig {
+    /// The NES's 2A03: a 6502 without decimal mode.
+    pub fn nes() -> Self {
+        Self::default()
+    }
+
+    /// A stock NMOS 6502, for running plain 6502 programs.
+    pub fn mos6502() -> Self {
+        CpuConfig {
+            decimal_mode: true,
+            ..Self::default()
+        }
+    }
+}
+
 #[derive(Debug)]
-pub struct CPU {
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU<M: Mem> {
     pub register_a: u8,
     pub register_x: u8,
     pub register_y: u8,
-    pub status: u8,
+    pub status: CpuFlags,
+    pub stack_pointer: u8,
     pub program_counter: u16,
-    memory: [u8; 0xFFFF],
+    /// CPU cycles elapsed since power-up, for keeping other components in lockstep.
+    pub cycles: u64,
+    pub config: CpuConfig,
+    /// The opcode that locked the CPU up, under [`UnknownOpcodePolicy::Jam`].
+    jammed: Option<u8>,
+    nmi_line: bool,
+    nmi_pending: bool,
+    irq_line: bool,
+    /// The I flag as it stood before a CLI, SEI or PLP. Those change I after the CPU has
+    /// already polled for interrupts, so the next poll still sees the old value.
+    irq_poll_disable: Option<bool>,
+    pub bus: M,
 }
 
-impl CPU {
+impl Default for CPU<Ram> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CPU<Ram> {
+    /// A CPU wired to a flat 64K of RAM.
     pub fn new() -> Self {
+        Self::with_bus(Ram::new())
+    }
+}
+
+impl CPU<Bus> {
+    /// Plugs `rom` into the NES bus and resets, so execution starts wherever the cartridge's
+    /// reset vector points.
+    pub fn from_rom(rom: Rom) -> Result<Self, RomError> {
+        let mut cpu = Self::with_bus(Bus::new(mapper::for_rom(rom)?));
+        cpu.reset();
+        Ok(cpu)
+    }
+}
+
+impl<M: Mem> Mem for CPU<M> {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.bus.mem_read(addr)
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.bus.mem_write(addr, data);
+    }
+
+    fn mem_peek(&self, addr: u16) -> u8 {
+        self.bus.mem_peek(addr)
+    }
+}
+
+impl<M: Mem> CPU<M> {
+    pub fn with_bus(bus: M) -> Self {
         Self {
             register_a: 0,
             register_x: 0,
             register_y: 0,
-            status: 0,
+            status: CpuFlags::empty(),
+            stack_pointer: STACK_RESET,
             program_counter: 0,
-            memory: [0; 0xFFFF],
+            cycles: 0,
+            config: CpuConfig::default(),
+            jammed: None,
+            nmi_line: false,
+            nmi_pending: false,
+            irq_line: false,
+            irq_poll_disable: None,
+            bus,
         }
     }
 
-    fn mem_read(&self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+    fn stack_push(&mut self, data: u8) {
+        self.mem_write(STACK + self.stack_pointer as u16, data);
+        self.stack_pointer = self.stack_pointer.wrapping_sub(1);
     }
 
-    fn mem_read_u16(&mut self, pos: u16) -> u16 {
-        let lo = self.mem_read(pos) as u16;
-        let hi = self.mem_read(pos + 1) as u16;
-        (hi << 8) | (lo as u16)
+    fn stack_pop(&mut self) -> u8 {
+        self.stack_pointer = self.stack_pointer.wrapping_add(1);
+        self.mem_read(STACK + self.stack_pointer as u16)
     }
 
-    fn mem_write(&mut self, addr: u16, data: u8) {
-        self.memory[addr as usize] = data;
+    fn stack_push_u16(&mut self, data: u16) {
+        self.stack_push((data >> 8) as u8);
+        self.stack_push((data & 0xFF) as u8);
+    }
+
+    fn stack_pop_u16(&mut self) -> u16 {
+        let lo = self.stack_pop() as u16;
+        let hi = self.stack_pop() as u16;
+        (hi << 8) | lo
+    }
+
+    fn stack_push_status(&mut self, break_flag: bool) {
+        self.stack_push(self.status.to_stack_byte(break_flag));
     }
 
-    fn mem_write_u16(&mut self, pos: u16, data: u16) {
-        let hi = (data >> 8) as u8;
-        let lo = (data & 0xFF) as u8;
-        self.mem_write(pos, lo);
-        self.mem_write(pos + 1, hi);
+    fn stack_pop_status(&mut self) {
+        self.status = CpuFlags::from_stack_byte(self.stack_pop());
     }
 
     pub fn reset(&mut self) {
         self.register_a = 0;
         self.register_x = 0;
-        self.status = 0;
+        self.register_y = 0;
+        self.status = CpuFlags::INTERRUPT_DISABLE | CpuFlags::UNUSED;
+        self.stack_pointer = STACK_RESET;
+        self.nmi_pending = false;
+        self.irq_poll_disable = None;
+        self.jammed = None;
 
-        self.program_counter = self.mem_read_u16(0xFFFC);
+        self.program_counter = self.mem_read_u16(RESET_VECTOR);
+        // the reset sequence takes 7 cycles before the first instruction is fetched
+        self.cycles += 7;
     }
 
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
-        self.load(program);
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> Result<(), CpuError> {
+        self.load(program)?;
         self.reset();
-        self.run();
+        self.run()
+    }
+
+    /// Copies `program` to $8000 and points the reset vector at it. A program long enough to
+    /// reach $FFFC has its reset vector overwritten.
+    pub fn load(&mut self, program: Vec<u8>) -> Result<(), CpuError> {
+        let max = 0x10000 - PROGRAM_START as usize;
+        if program.len() > max {
+            return Err(CpuError::ProgramTooLarge {
+                len: program.len(),
+                max,
+            });
+        }
+
+        for (i, byte) in program.iter().enumerate() {
+            self.mem_write(PROGRAM_START.wrapping_add(i as u16), *byte);
+        }
+        self.mem_write_u16(RESET_VECTOR, PROGRAM_START);
+        Ok(())
+    }
+
+    /// Drives the NMI input. NMI is edge-triggered: only an inactive-to-active transition
+    /// latches an interrupt, which is taken before the next instruction.
+    pub fn set_nmi_line(&mut self, active: bool) {
+        if active && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = active;
+    }
+
+    /// Drives the IRQ input. IRQ is level-triggered: it is taken at every instruction boundary
+    /// for as long as the line stays active and the I flag is clear, so the source must
+    /// acknowledge it by releasing the line.
+    pub fn set_irq_line(&mut self, active: bool) {
+        self.irq_line = active;
+    }
+
+    fn interrupt(&mut self, vector: u16, break_flag: bool) {
+        self.stack_push_u16(self.program_counter);
+        self.stack_push_status(break_flag);
+        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+        self.program_counter = self.mem_read_u16(vector);
+    }
+
+    /// Remembers the I flag for the next interrupt poll before an instruction changes it.
+    fn delay_interrupt_disable(&mut self) {
+        self.irq_poll_disable = Some(self.status.contains(CpuFlags::INTERRUPT_DISABLE));
+    }
+
+    fn poll_interrupts(&mut self) -> Option<Interrupt> {
+        let irq_disabled = self
+            .irq_poll_disable
+            .take()
+            .unwrap_or(self.status.contains(CpuFlags::INTERRUPT_DISABLE));
+
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.interrupt(NMI_VECTOR, false);
+            self.cycles += INTERRUPT_CYCLES;
+            Some(Interrupt::Nmi)
+        } else if self.irq_line && !irq_disabled {
+            self.interrupt(IRQ_VECTOR, false);
+            self.cycles += INTERRUPT_CYCLES;
+            Some(Interrupt::Irq)
+        } else {
+            None
+        }
+    }
+
+    /// Runs until a BRK instruction has been serviced, then hands control back to the caller.
+    pub fn run(&mut self) -> Result<(), CpuError> {
+        self.run_with_callback(|_| {})
+    }
+
+    /// Like [`CPU::run`], but hands the CPU to `callback` before every instruction so it can
+    /// trace, poll input, refresh the screen or poke at state between instructions.
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<(), CpuError>
+    where
+        F: FnMut(&mut CPU<M>),
+    {
+        loop {
+            callback(self);
+            if self.step()?.opcode == 0x00 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs whole instructions until at least `cycles` CPU cycles have elapsed, returning how
+    /// many actually did. The last instruction may overshoot the budget.
+    pub fn run_for_cycles(&mut self, cycles: u64) -> Result<u64, CpuError> {
+        let start = self.cycles;
+        while self.cycles - start < cycles {
+            self.step()?;
+        }
+        Ok(self.cycles - start)
     }
 
-    pub fn load(&mut self, program: Vec<u8>) {
-        self.memory[0x8000..(0x8000 + program.len())].copy_from_slice(&program[..]);
-        self.mem_write_u16(0xFFFC, 0x8000);
+    /// Runs until `predicate` holds, checking it before every instruction.
+    pub fn run_until<F>(&mut self, mut predicate: F) -> Result<(), CpuError>
+    where
+        F: FnMut(&CPU<M>) -> bool,
+    {
+        while !predicate(self) {
+            self.step()?;
+        }
+        Ok(())
     }
 
-    pub fn run(&mut self) {
+    /// Runs until an instruction jumps or branches to itself, the way test ROMs like Klaus
+    /// Dormann's signal that they have finished or failed, and returns the trap's address.
+    pub fn run_until_trap(&mut self) -> Result<u16, CpuError> {
         loop {
-            let opcode = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-
-            match opcode {
-                0x00 => return,
-                0xA1 => {
-                    self.lda(&AddressingMode::IndirectX);
-                    self.program_counter;
-                }
-                0xA5 => {
-                    self.lda(&AddressingMode::ZeroPage);
-                    self.program_counter += 1;
-                }
-                0xA9 => {
-                    self.lda(&AddressingMode::Immediate);
-                    self.program_counter += 1;
-                }
-                0xAA => self.tax(),
-                0xAD => {
-                    self.lda(&AddressingMode::Absolute);
-                    self.program_counter += 1;
-                }
-                0xB1 => {
-                    self.lda(&AddressingMode::IndirectY);
-                    self.program_counter += 1;
-                }
-                0xB5 => {
-                    self.lda(&AddressingMode::ZeroPageX);
-                    self.program_counter += 1;
-                }
-                0xB9 => {
-                    self.lda(&AddressingMode::AbsoluteY);
-                    self.program_counter += 1;
-                }
-                0xBD => {
-                    self.lda(&AddressingMode::AbsoluteX);
-                    self.program_counter += 1;
-                }
-                0xE8 => self.inx(),
-                _ => {}
+            let step = self.step()?;
+            if step.interrupt.is_none() && self.program_counter == step.address {
+                return Ok(step.address);
+            }
+        }
+    }
+
+    /// Executes exactly one instruction, first taking a pending interrupt if there is one,
+    /// in which case the instruction is the first of the handler.
+    pub fn step(&mut self) -> Result<Step, CpuError> {
+        if let Some(opcode) = self.jammed {
+            return Err(CpuError::Jammed {
+                opcode,
+                program_counter: self.program_counter,
+            });
+        }
+
+        let start_cycles = self.cycles;
+        let interrupt = self.poll_interrupts();
+
+        let address = self.program_counter;
+        let code = self.mem_read(address);
+        self.program_counter = address.wrapping_add(1);
+
+        let step = |cpu: &Self| Step {
+            address,
+            opcode: code,
+            cycles: cpu.cycles - start_cycles,
+            interrupt,
+        };
+
+        let opcode = match opcodes::lookup(code) {
+            Some(opcode) if opcode.is_official() || self.config.unofficial_opcodes => opcode,
+            _ => {
+                self.unknown_opcode(code)?;
+                return Ok(step(self));
+            }
+        };
+
+        self.cycles += opcode.cycles as u64;
+
+        // set by the jumps, branches and returns that leave the PC somewhere else
+        let mut jumped = false;
+
+        match code {
+            0x00 => {
+                self.brk();
+                jumped = true;
+            }
+            0xea => {}
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode)?,
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => self.sbc(&opcode.mode)?,
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode)?,
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode)?,
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode)?,
+            0x0a => self.asl_accumulator(),
+            0x06 | 0x16 | 0x0e | 0x1e => {
+                self.asl(&opcode.mode)?;
+            }
+            0x4a => self.lsr_accumulator(),
+            0x46 | 0x56 | 0x4e | 0x5e => {
+                self.lsr(&opcode.mode)?;
+            }
+            0x2a => self.rol_accumulator(),
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol(&opcode.mode)?;
+            }
+            0x6a => self.ror_accumulator(),
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror(&opcode.mode)?;
+            }
+            0xe6 | 0xf6 | 0xee | 0xfe => {
+                self.inc(&opcode.mode)?;
+            }
+            0xe8 => self.inx(),
+            0xc8 => self.iny(),
+            0xc6 | 0xd6 | 0xce | 0xde => {
+                self.dec(&opcode.mode)?;
+            }
+            0xca => self.dex(),
+            0x88 => self.dey(),
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
+                self.compare(&opcode.mode, self.register_a)?
+            }
+            0xc0 | 0xc4 | 0xcc => self.compare(&opcode.mode, self.register_y)?,
+            0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x)?,
+            0x4c | 0x6c => {
+                self.jmp(&opcode.mode)?;
+                jumped = true;
+            }
+            0x20 => {
+                self.jsr();
+                jumped = true;
+            }
+            0x60 => {
+                self.rts();
+                jumped = true;
+            }
+            0x40 => {
+                self.rti();
+                jumped = true;
+            }
+            0xd0 => jumped = self.branch(!self.status.contains(CpuFlags::ZERO)),
+            0x70 => jumped = self.branch(self.status.contains(CpuFlags::OVERFLOW)),
+            0x50 => jumped = self.branch(!self.status.contains(CpuFlags::OVERFLOW)),
+            0x30 => jumped = self.branch(self.status.contains(CpuFlags::NEGATIVE)),
+            0xf0 => jumped = self.branch(self.status.contains(CpuFlags::ZERO)),
+            0xb0 => jumped = self.branch(self.status.contains(CpuFlags::CARRY)),
+            0x90 => jumped = self.branch(!self.status.contains(CpuFlags::CARRY)),
+            0x10 => jumped = self.branch(!self.status.contains(CpuFlags::NEGATIVE)),
+            0x24 | 0x2c => self.bit(&opcode.mode)?,
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.lda(&opcode.mode)?,
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => self.ldx(&opcode.mode)?,
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => self.ldy(&opcode.mode)?,
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode)?,
+            0x86 | 0x96 | 0x8e => self.stx(&opcode.mode)?,
+            0x84 | 0x94 | 0x8c => self.sty(&opcode.mode)?,
+            0xd8 => self.status.remove(CpuFlags::DECIMAL_MODE),
+            0x58 => {
+                self.delay_interrupt_disable();
+                self.status.remove(CpuFlags::INTERRUPT_DISABLE);
+            }
+            0xb8 => self.status.remove(CpuFlags::OVERFLOW),
+            0x18 => self.status.remove(CpuFlags::CARRY),
+            0x38 => self.status.insert(CpuFlags::CARRY),
+            0x78 => {
+                self.delay_interrupt_disable();
+                self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+            }
+            0xf8 => self.status.insert(CpuFlags::DECIMAL_MODE),
+            0xaa => self.tax(),
+            0xa8 => self.tay(),
+            0xba => self.tsx(),
+            0x8a => self.txa(),
+            0x9a => self.txs(),
+            0x98 => self.tya(),
+            0x48 => self.pha(),
+            0x68 => self.pla(),
+            0x08 => self.php(),
+            0x28 => {
+                self.delay_interrupt_disable();
+                self.plp();
+            }
+
+            // unofficial
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa | 0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 | 0x04
+            | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c | 0x3c | 0x5c
+            | 0x7c | 0xdc | 0xfc => self.nop_read(&opcode.mode)?,
+            0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => self.lax(&opcode.mode)?,
+            0x87 | 0x97 | 0x8f | 0x83 => self.sax(&opcode.mode)?,
+            0xeb => self.sbc(&opcode.mode)?,
+            0xc7 | 0xd7 | 0xcf | 0xdf | 0xdb | 0xc3 | 0xd3 => self.dcp(&opcode.mode)?,
+            0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => self.isb(&opcode.mode)?,
+            0x07 | 0x17 | 0x0f | 0x1f | 0x1b | 0x03 | 0x13 => self.slo(&opcode.mode)?,
+            0x27 | 0x37 | 0x2f | 0x3f | 0x3b | 0x23 | 0x33 => self.rla(&opcode.mode)?,
+            0x47 | 0x57 | 0x4f | 0x5f | 0x5b | 0x43 | 0x53 => self.sre(&opcode.mode)?,
+            0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => self.rra(&opcode.mode)?,
+            0x0b | 0x2b => self.anc(&opcode.mode)?,
+            0x4b => self.alr(&opcode.mode)?,
+            0x6b => self.arr(&opcode.mode)?,
+            0xcb => self.axs(&opcode.mode)?,
+            _ => unreachable!("opcode {:#04x} is in the table but not decoded", code),
+        }
+
+        if !jumped {
+            self.program_counter = self.program_counter.wrapping_add((opcode.len - 1) as u16);
+        }
+
+        Ok(step(self))
+    }
+
+    fn unknown_opcode(&mut self, code: u8) -> Result<(), CpuError> {
+        let program_counter = self.program_counter.wrapping_sub(1);
+
+        match self.config.unknown_opcodes {
+            UnknownOpcodePolicy::Error => {
+                self.program_counter = program_counter;
+                Err(CpuError::UnknownOpcode {
+                    opcode: code,
+                    program_counter,
+                })
+            }
+            UnknownOpcodePolicy::Nop => {
+                self.cycles += 2;
+                Ok(())
+            }
+            UnknownOpcodePolicy::Jam => {
+                self.program_counter = program_counter;
+                self.jammed = Some(code);
+                Err(CpuError::Jammed {
+                    opcode: code,
+                    program_counter,
+                })
             }
         }
     }
 
-    fn lda(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    fn lda(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
         let value = self.mem_read(addr);
 
         self.register_a = value;
         self.update_zero_and_negative_flags(self.register_a);
+        Ok(())
+    }
+
+    fn ldx(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        self.register_x = self.mem_read(addr);
+        self.update_zero_and_negative_flags(self.register_x);
+        Ok(())
+    }
+
+    fn ldy(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        self.register_y = self.mem_read(addr);
+        self.update_zero_and_negative_flags(self.register_y);
+        Ok(())
+    }
+
+    fn sta(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        self.mem_write(addr, self.register_a);
+        Ok(())
+    }
+
+    fn stx(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        self.mem_write(addr, self.register_x);
+        Ok(())
+    }
+
+    fn sty(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        self.mem_write(addr, self.register_y);
+        Ok(())
     }
 
     fn tax(&mut self) {
@@ -121,183 +621,1726 @@ impl CPU {
         self.update_zero_and_negative_flags(self.register_x);
     }
 
+    fn tay(&mut self) {
+        self.register_y = self.register_a;
+        self.update_zero_and_negative_flags(self.register_y);
+    }
+
+    fn txa(&mut self) {
+        self.register_a = self.register_x;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    fn tya(&mut self) {
+        self.register_a = self.register_y;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    fn tsx(&mut self) {
+        self.register_x = self.stack_pointer;
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    fn txs(&mut self) {
+        self.stack_pointer = self.register_x;
+    }
+
     fn inx(&mut self) {
         self.register_x = self.register_x.wrapping_add(1);
         self.update_zero_and_negative_flags(self.register_x);
     }
 
-    fn update_zero_and_negative_flags(&mut self, result: u8) {
-        if result == 0 {
-            self.status = self.status | 0b0000_0010;
-        } else {
-            self.status = self.status & 0b1111_1101;
-        }
+    fn iny(&mut self) {
+        self.register_y = self.register_y.wrapping_add(1);
+        self.update_zero_and_negative_flags(self.register_y);
+    }
 
-        if result & 0b1000_0000 != 0 {
-            self.status = self.status | 0b1000_0000;
-        } else {
-            self.status = self.status & 0b0111_1111;
-        }
+    fn dex(&mut self) {
+        self.register_x = self.register_x.wrapping_sub(1);
+        self.update_zero_and_negative_flags(self.register_x);
     }
 
-    fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
-        match mode {
-            AddressingMode::Immediate => self.program_counter,
-            AddressingMode::ZeroPage => self.mem_read(self.program_counter) as u16,
-            AddressingMode::Absolute => self.mem_read_u16(self.program_counter),
-            AddressingMode::ZeroPageX => {
-                let pos = self.mem_read(self.program_counter);
-                let addr = pos.wrapping_add(self.register_x) as u16;
-                addr
-            }
-            AddressingMode::ZeroPageY => {
-                let pos = self.mem_read(self.program_counter);
-                let addr = pos.wrapping_add(self.register_y) as u16;
-                addr
-            }
-            AddressingMode::AbsoluteX => {
-                let base = self.mem_read_u16(self.program_counter);
-                let addr = base.wrapping_add(self.register_x as u16);
-                addr
-            }
-            AddressingMode::AbsoluteY => {
-                let base = self.mem_read_u16(self.program_counter);
-                let addr = base.wrapping_add(self.register_y as u16);
-                addr
-            }
-            AddressingMode::IndirectX => {
-                let base = self.mem_read(self.program_counter);
-                let ptr: u8 = (base as u8).wrapping_add(self.register_x);
-                let lo = self.mem_read(ptr as u16);
-                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
-                (hi as u16) << 8 | (lo as u16)
-            }
-            AddressingMode::IndirectY => {
-                let base = self.mem_read(self.program_counter);
-
-                let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
-                let deref_base = (hi as u16) << 8 | (lo as u16);
-                let deref = deref_base.wrapping_add(self.register_y as u16);
-                deref
-            }
-            AddressingMode::NoneAddressing => panic!("Mode {:?} is not supported", mode),
-        }
+    fn dey(&mut self) {
+        self.register_y = self.register_y.wrapping_sub(1);
+        self.update_zero_and_negative_flags(self.register_y);
     }
-}
 
-#[derive(Debug)]
-pub enum AddressingMode {
-    Immediate,
-    ZeroPage,
-    ZeroPageX,
-    ZeroPageY,
-    Absolute,
-    AbsoluteX,
-    AbsoluteY,
-    IndirectX,
-    IndirectY,
-    NoneAddressing,
-}
+    /// Read-modify-write instructions write the unmodified value back on the cycle they
+    /// compute the result, then write the result on the next. MMC1 notices the double write.
+    fn write_modified(&mut self, addr: u16, original: u8, result: u8) {
+        self.mem_write(addr, original);
+        self.mem_write(addr, result);
+    }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    fn inc(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        let original = self.mem_read(addr);
+        let value = original.wrapping_add(1);
+        self.write_modified(addr, original, value);
+        self.update_zero_and_negative_flags(value);
+        Ok(value)
+    }
 
-    #[test]
-    fn lda_immediate() {
-        let mut cpu = CPU::new();
-        let program = vec![0xA9, 0x05, 0x00];
-        cpu.load_and_run(program);
+    fn dec(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        let original = self.mem_read(addr);
+        let value = original.wrapping_sub(1);
+        self.write_modified(addr, original, value);
+        self.update_zero_and_negative_flags(value);
+        Ok(value)
+    }
 
-        assert_eq!(cpu.register_a, 0x05);
+    fn and(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        self.register_a &= self.mem_read(addr);
+        self.update_zero_and_negative_flags(self.register_a);
+        Ok(())
     }
 
-    #[test]
-    fn lda_zero_page() {
-        let mut cpu = CPU::new();
-        let program = vec![0xA5, 0x80, 0x00];
-        cpu.mem_write(0x80, 0xFF);
-        cpu.load_and_run(program);
+    fn eor(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        self.register_a ^= self.mem_read(addr);
+        self.update_zero_and_negative_flags(self.register_a);
+        Ok(())
+    }
 
-        assert_eq!(cpu.register_a, 0xFF);
+    fn ora(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        self.register_a |= self.mem_read(addr);
+        self.update_zero_and_negative_flags(self.register_a);
+        Ok(())
     }
 
-    #[test]
-    fn lda_zero_page_x() {
-        let mut cpu = CPU::new();
-        let program = vec![0xB5, 0x80, 0x00];
-        cpu.mem_write(0x8F, 0xFF);
-        cpu.load(program);
-        cpu.reset();
-        cpu.register_x = 0x0F;
-        cpu.run();
+    fn adc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        let value = self.mem_read(addr);
+        self.add_with_carry(value);
+        Ok(())
+    }
 
-        assert_eq!(cpu.register_a, 0xFF);
+    fn sbc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        let value = self.mem_read(addr);
+        self.subtract_with_borrow(value);
+        Ok(())
     }
 
-    #[test]
-    fn lda_absolute() {
-        let mut cpu = CPU::new();
-        let program = vec![0xAD, 0xAD, 0xDE, 0x00];
-        cpu.mem_write(0xDEAD, 0xFF);
-        cpu.load_and_run(program);
+    fn decimal_active(&self) -> bool {
+        self.config.decimal_mode && self.status.contains(CpuFlags::DECIMAL_MODE)
+    }
 
-        assert_eq!(cpu.register_a, 0xFF);
+    fn add_with_carry(&mut self, value: u8) {
+        if self.decimal_active() {
+            self.add_decimal(value);
+        } else {
+            self.add_to_register_a(value);
+        }
     }
 
-    #[test]
-    fn lda_absolute_x() {
-        let mut cpu = CPU::new();
-        let program = vec![0xBD, 0x00, 0xDE, 0x00];
-        cpu.mem_write(0xDEAD, 0xFF);
-        cpu.load(program);
-        cpu.reset();
-        cpu.register_x = 0xAD;
-        cpu.run();
+    fn subtract_with_borrow(&mut self, value: u8) {
+        if self.decimal_active() {
+            self.subtract_decimal(value);
+        } else {
+            // A - M - (1 - C) == A + !M + C
+            self.add_to_register_a(!value);
+        }
+    }
 
-        assert_eq!(cpu.register_a, 0xFF);
+    fn add_to_register_a(&mut self, value: u8) {
+        let sum =
+            self.register_a as u16 + value as u16 + self.status.contains(CpuFlags::CARRY) as u16;
+        let result = sum as u8;
+
+        self.status.set(CpuFlags::CARRY, sum > 0xFF);
+        // signed overflow: both operands share a sign that differs from the result's
+        self.status.set(
+            CpuFlags::OVERFLOW,
+            (value ^ result) & (self.register_a ^ result) & 0x80 != 0,
+        );
+
+        self.register_a = result;
+        self.update_zero_and_negative_flags(self.register_a);
     }
 
-    #[test]
-    fn lda_absolute_y() {
-        let mut cpu = CPU::new();
-        let program = vec![0xB9, 0x00, 0xDE, 0x00];
-        cpu.mem_write(0xDEAD, 0xFF);
-        cpu.load(program);
-        cpu.reset();
-        cpu.register_y = 0xAD;
-        cpu.run();
+    /// NMOS BCD addition. Z comes from the binary sum, N and V from the sum after only the
+    /// low digit has been adjusted, and C from the fully adjusted sum.
+    fn add_decimal(&mut self, value: u8) {
+        let a = self.register_a as u16;
+        let m = value as u16;
+        let carry = self.status.contains(CpuFlags::CARRY) as u16;
 
-        assert_eq!(cpu.register_a, 0xFF);
+        let mut sum = (a & 0x0F) + (m & 0x0F) + carry;
+        if sum > 0x09 {
+            sum += 0x06;
+        }
+        sum = (sum & 0x0F) + (a & 0xF0) + (m & 0xF0) + if sum > 0x0F { 0x10 } else { 0 };
+
+        self.status.set(CpuFlags::ZERO, (a + m + carry) & 0xFF == 0);
+        self.status.set(CpuFlags::NEGATIVE, sum & 0x80 != 0);
+        self.status.set(
+            CpuFlags::OVERFLOW,
+            (a ^ sum) & 0x80 != 0 && (a ^ m) & 0x80 == 0,
+        );
+
+        if sum & 0x1F0 > 0x90 {
+            sum += 0x60;
+        }
+        self.status.set(CpuFlags::CARRY, sum & 0xFF0 > 0xF0);
+        self.register_a = sum as u8;
     }
 
-    #[test]
-    fn lda_indirect_x() {
-        let mut cpu = CPU::new();
-        let program = vec![0xA1, 0x02, 0x00];
-        cpu.mem_write_u16(0x04, 0x8086);
-        cpu.mem_write(0x8086, 0xFF);
-        cpu.load(program);
-        cpu.reset();
-        cpu.register_x = 0x02;
-        cpu.run();
-        //println!("{:?}", cpu.memory);
+    /// NMOS BCD subtraction. The flags are exactly those of the binary subtraction; only the
+    /// result is adjusted.
+    fn subtract_decimal(&mut self, value: u8) {
+        let a = self.register_a as i16;
+        let m = value as i16;
+        let borrow = !self.status.contains(CpuFlags::CARRY) as i16;
 
-        assert_eq!(cpu.register_a, 0xFF);
+        let mut low = (a & 0x0F) - (m & 0x0F) - borrow;
+        let mut high = (a & 0xF0) - (m & 0xF0);
+        if low < 0 {
+            low -= 0x06;
+            high -= 0x10;
+        }
+        if high < 0 {
+            high -= 0x60;
+        }
+        let result = ((low & 0x0F) | high) as u8;
+
+        self.add_to_register_a(!value);
+        self.register_a = result;
     }
 
-    #[test]
+    fn compare(&mut self, mode: &AddressingMode, register: u8) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        let value = self.mem_read(addr);
+        self.compare_value(register, value);
+        Ok(())
+    }
+
+    fn compare_value(&mut self, register: u8, value: u8) {
+        self.status.set(CpuFlags::CARRY, register >= value);
+        self.update_zero_and_negative_flags(register.wrapping_sub(value));
+    }
+
+    fn bit(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        let value = self.mem_read(addr);
+
+        self.status
+            .set(CpuFlags::ZERO, self.register_a & value == 0);
+        self.status
+            .set(CpuFlags::NEGATIVE, value & 0b1000_0000 != 0);
+        self.status
+            .set(CpuFlags::OVERFLOW, value & 0b0100_0000 != 0);
+        Ok(())
+    }
+
+    fn asl_accumulator(&mut self) {
+        let value = self.register_a;
+        self.status.set(CpuFlags::CARRY, value & 0b1000_0000 != 0);
+        self.register_a = value << 1;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    fn asl(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        let value = self.mem_read(addr);
+        self.status.set(CpuFlags::CARRY, value & 0b1000_0000 != 0);
+        let result = value << 1;
+        self.write_modified(addr, value, result);
+        self.update_zero_and_negative_flags(result);
+        Ok(result)
+    }
+
+    fn lsr_accumulator(&mut self) {
+        let value = self.register_a;
+        self.status.set(CpuFlags::CARRY, value & 0b0000_0001 != 0);
+        self.register_a = value >> 1;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    fn lsr(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        let value = self.mem_read(addr);
+        self.status.set(CpuFlags::CARRY, value & 0b0000_0001 != 0);
+        let result = value >> 1;
+        self.write_modified(addr, value, result);
+        self.update_zero_and_negative_flags(result);
+        Ok(result)
+    }
+
+    fn rol_accumulator(&mut self) {
+        let value = self.register_a;
+        let carry_in = self.status.contains(CpuFlags::CARRY) as u8;
+        self.status.set(CpuFlags::CARRY, value & 0b1000_0000 != 0);
+        self.register_a = (value << 1) | carry_in;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    fn rol(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        let value = self.mem_read(addr);
+        let carry_in = self.status.contains(CpuFlags::CARRY) as u8;
+        self.status.set(CpuFlags::CARRY, value & 0b1000_0000 != 0);
+        let result = (value << 1) | carry_in;
+        self.write_modified(addr, value, result);
+        self.update_zero_and_negative_flags(result);
+        Ok(result)
+    }
+
+    fn ror_accumulator(&mut self) {
+        let value = self.register_a;
+        let carry_in = (self.status.contains(CpuFlags::CARRY) as u8) << 7;
+        self.status.set(CpuFlags::CARRY, value & 0b0000_0001 != 0);
+        self.register_a = (value >> 1) | carry_in;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    fn ror(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        let value = self.mem_read(addr);
+        let carry_in = (self.status.contains(CpuFlags::CARRY) as u8) << 7;
+        self.status.set(CpuFlags::CARRY, value & 0b0000_0001 != 0);
+        let result = (value >> 1) | carry_in;
+        self.write_modified(addr, value, result);
+        self.update_zero_and_negative_flags(result);
+        Ok(result)
+    }
+
+    /// Returns whether the branch was taken, in which case it has already moved the PC.
+    fn branch(&mut self, condition: bool) -> bool {
+        if condition {
+            let offset = self.mem_read(self.program_counter) as i8;
+            let next = self.program_counter.wrapping_add(1);
+            let target = next.wrapping_add(offset as u16);
+
+            // a taken branch costs one extra cycle, two if it lands on another page
+            self.cycles += 1;
+            if page_crossed(next, target) {
+                self.cycles += 1;
+            }
+
+            self.program_counter = target;
+        }
+        condition
+    }
+
+    fn jmp(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        self.program_counter = addr;
+        Ok(())
+    }
+
+    fn brk(&mut self) {
+        // BRK has a padding byte, so the return address skips past it
+        self.program_counter = self.program_counter.wrapping_add(1);
+        self.interrupt(IRQ_VECTOR, true);
+    }
+
+    fn jsr(&mut self) {
+        // the 6502 pushes the address of the last byte of the JSR instruction
+        self.stack_push_u16(self.program_counter.wrapping_add(1));
+        self.program_counter = self.mem_read_u16(self.program_counter);
+    }
+
+    fn rts(&mut self) {
+        self.program_counter = self.stack_pop_u16().wrapping_add(1);
+    }
+
+    fn rti(&mut self) {
+        self.stack_pop_status();
+        self.program_counter = self.stack_pop_u16();
+    }
+
+    fn pha(&mut self) {
+        self.stack_push(self.register_a);
+    }
+
+    fn pla(&mut self) {
+        self.register_a = self.stack_pop();
+        self.update_zero_and_negative_flags(self.register_a);
+    }
+
+    fn php(&mut self) {
+        self.stack_push_status(true);
+    }
+
+    fn plp(&mut self) {
+        self.stack_pop_status();
+    }
+
+    fn nop_read(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        // the multi-byte NOPs still perform (and pay for) their read
+        if *mode != AddressingMode::NoneAddressing {
+            let addr = self.get_read_address(mode)?;
+            self.mem_read(addr);
+        }
+        Ok(())
+    }
+
+    fn lax(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        self.lda(mode)?;
+        self.tax();
+        Ok(())
+    }
+
+    fn sax(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let (addr, _) = self.get_operand_address(mode)?;
+        self.mem_write(addr, self.register_a & self.register_x);
+        Ok(())
+    }
+
+    fn dcp(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let value = self.dec(mode)?;
+        self.compare_value(self.register_a, value);
+        Ok(())
+    }
+
+    fn isb(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let value = self.inc(mode)?;
+        self.subtract_with_borrow(value);
+        Ok(())
+    }
+
+    fn slo(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        self.register_a |= self.asl(mode)?;
+        self.update_zero_and_negative_flags(self.register_a);
+        Ok(())
+    }
+
+    fn rla(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        self.register_a &= self.rol(mode)?;
+        self.update_zero_and_negative_flags(self.register_a);
+        Ok(())
+    }
+
+    fn sre(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        self.register_a ^= self.lsr(mode)?;
+        self.update_zero_and_negative_flags(self.register_a);
+        Ok(())
+    }
+
+    fn rra(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let value = self.ror(mode)?;
+        self.add_with_carry(value);
+        Ok(())
+    }
+
+    fn anc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        self.and(mode)?;
+        self.status
+            .set(CpuFlags::CARRY, self.status.contains(CpuFlags::NEGATIVE));
+        Ok(())
+    }
+
+    fn alr(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        self.and(mode)?;
+        self.lsr_accumulator();
+        Ok(())
+    }
+
+    fn arr(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        self.and(mode)?;
+        if self.decimal_active() {
+            self.arr_decimal();
+            return Ok(());
+        }
+        self.ror_accumulator();
+
+        // C and V come from bits 6 and 5 of the rotated result
+        let result = self.register_a;
+        self.status.set(CpuFlags::CARRY, result & 0b0100_0000 != 0);
+        self.status
+            .set(CpuFlags::OVERFLOW, ((result >> 6) ^ (result >> 5)) & 1 != 0);
+        Ok(())
+    }
+
+    /// ARR with D set: N and Z come from the rotate, V from bit 6 changing, and each digit
+    /// of the rotated value is then BCD-fixed based on the digit it was rotated from, with C
+    /// set by the high digit's fix-up.
+    fn arr_decimal(&mut self) {
+        let and = self.register_a;
+        self.ror_accumulator();
+        let mut result = self.register_a;
+        self.status
+            .set(CpuFlags::OVERFLOW, (and ^ result) & 0b0100_0000 != 0);
+
+        let low = and & 0x0F;
+        let high = and >> 4;
+        if low + (low & 1) > 5 {
+            result = (result & 0xF0) | (result.wrapping_add(6) & 0x0F);
+        }
+        let carry = high + (high & 1) > 5;
+        if carry {
+            result = result.wrapping_add(0x60);
+        }
+        self.status.set(CpuFlags::CARRY, carry);
+        self.register_a = result;
+    }
+
+    fn axs(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_read_address(mode)?;
+        let value = self.mem_read(addr);
+        let and = self.register_a & self.register_x;
+
+        // a compare rather than a subtraction: no borrow in, V untouched
+        self.status.set(CpuFlags::CARRY, and >= value);
+        self.register_x = and.wrapping_sub(value);
+        self.update_zero_and_negative_flags(self.register_x);
+        Ok(())
+    }
+
+    fn update_zero_and_negative_flags(&mut self, result: u8) {
+        self.status.set(CpuFlags::ZERO, result == 0);
+        self.status
+            .set(CpuFlags::NEGATIVE, result & 0b1000_0000 != 0);
+    }
+
+    /// Resolves the read address for an instruction that only loads its operand, charging the
+    /// extra cycle an indexed read takes when it crosses a page boundary.
+    fn get_read_address(&mut self, mode: &AddressingMode) -> Result<u16, CpuError> {
+        let (addr, page_cross) = self.get_operand_address(mode)?;
+        if page_cross {
+            self.cycles += 1;
+        }
+        Ok(addr)
+    }
+
+    fn get_operand_address(&mut self, mode: &AddressingMode) -> Result<(u16, bool), CpuError> {
+        self.get_absolute_address(mode, self.program_counter)
+    }
+
+    /// Returns the effective address `mode` resolves to for an operand stored at `addr`, and
+    /// whether indexing crossed a page boundary.
+    pub fn get_absolute_address(
+        &mut self,
+        mode: &AddressingMode,
+        addr: u16,
+    ) -> Result<(u16, bool), CpuError> {
+        let (x, y) = (self.register_x, self.register_y);
+        resolve_address(mode, addr, x, y, |addr| self.bus.mem_read(addr))
+    }
+
+    /// Like [`CPU::get_absolute_address`], but reads through [`Mem::mem_peek`] so that
+    /// looking at an instruction (to trace it, say) can't disturb the hardware.
+    pub fn peek_absolute_address(
+        &self,
+        mode: &AddressingMode,
+        addr: u16,
+    ) -> Result<(u16, bool), CpuError> {
+        resolve_address(mode, addr, self.register_x, self.register_y, |addr| {
+            self.bus.mem_peek(addr)
+        })
+    }
+}
+
+/// The addressing modes, over whichever kind of read the caller wants.
+fn resolve_address<R: FnMut(u16) -> u8>(
+    mode: &AddressingMode,
+    addr: u16,
+    x: u8,
+    y: u8,
+    mut read: R,
+) -> Result<(u16, bool), CpuError> {
+    fn read_u16<R: FnMut(u16) -> u8>(read: &mut R, pos: u16) -> u16 {
+        let lo = read(pos) as u16;
+        let hi = read(pos.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
+    let operand = match mode {
+        AddressingMode::Immediate => (addr, false),
+        AddressingMode::ZeroPage => (read(addr) as u16, false),
+        AddressingMode::Absolute => (read_u16(&mut read, addr), false),
+        AddressingMode::ZeroPageX => {
+            let pos = read(addr);
+            (pos.wrapping_add(x) as u16, false)
+        }
+        AddressingMode::ZeroPageY => {
+            let pos = read(addr);
+            (pos.wrapping_add(y) as u16, false)
+        }
+        AddressingMode::AbsoluteX => {
+            let base = read_u16(&mut read, addr);
+            let target = base.wrapping_add(x as u16);
+            (target, page_crossed(base, target))
+        }
+        AddressingMode::AbsoluteY => {
+            let base = read_u16(&mut read, addr);
+            let target = base.wrapping_add(y as u16);
+            (target, page_crossed(base, target))
+        }
+        AddressingMode::IndirectX => {
+            let base = read(addr);
+            let ptr: u8 = base.wrapping_add(x);
+            let lo = read(ptr as u16);
+            let hi = read(ptr.wrapping_add(1) as u16);
+            ((hi as u16) << 8 | (lo as u16), false)
+        }
+        AddressingMode::IndirectY => {
+            let base = read(addr);
+
+            let lo = read(base as u16);
+            let hi = read(base.wrapping_add(1) as u16);
+            let deref_base = (hi as u16) << 8 | (lo as u16);
+            let deref = deref_base.wrapping_add(y as u16);
+            (deref, page_crossed(deref_base, deref))
+        }
+        AddressingMode::Indirect => {
+            let ptr = read_u16(&mut read, addr);
+
+            // the 6502 never carries into the pointer's high byte, so a pointer at
+            // $xxFF takes its high byte from $xx00 instead of the next page
+            let lo = read(ptr);
+            let hi = read((ptr & 0xFF00) | (ptr.wrapping_add(1) & 0x00FF));
+            ((hi as u16) << 8 | (lo as u16), false)
+        }
+        AddressingMode::NoneAddressing => return Err(CpuError::BadAddressingMode(*mode)),
+    };
+    Ok(operand)
+}
+
+fn page_crossed(a: u16, b: u16) -> bool {
+    a & 0xFF00 != b & 0xFF00
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressingMode {
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    IndirectX,
+    IndirectY,
+    Indirect,
+    NoneAddressing,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lda_immediate() {
+        let mut cpu = CPU::new();
+        let program = vec![0xA9, 0x05, 0x00];
+        cpu.load_and_run(program).unwrap();
+
+        assert_eq!(cpu.register_a, 0x05);
+    }
+
+    #[test]
+    fn lda_zero_page() {
+        let mut cpu = CPU::new();
+        let program = vec![0xA5, 0x80, 0x00];
+        cpu.mem_write(0x80, 0xFF);
+        cpu.load_and_run(program).unwrap();
+
+        assert_eq!(cpu.register_a, 0xFF);
+    }
+
+    #[test]
+    fn lda_zero_page_x() {
+        let mut cpu = CPU::new();
+        let program = vec![0xB5, 0x80, 0x00];
+        cpu.mem_write(0x8F, 0xFF);
+        cpu.load(program).unwrap();
+        cpu.reset();
+        cpu.register_x = 0x0F;
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.register_a, 0xFF);
+    }
+
+    #[test]
+    fn lda_absolute() {
+        let mut cpu = CPU::new();
+        let program = vec![0xAD, 0xAD, 0xDE, 0x00];
+        cpu.mem_write(0xDEAD, 0xFF);
+        cpu.load_and_run(program).unwrap();
+
+        assert_eq!(cpu.register_a, 0xFF);
+    }
+
+    #[test]
+    fn lda_absolute_x() {
+        let mut cpu = CPU::new();
+        let program = vec![0xBD, 0x00, 0xDE, 0x00];
+        cpu.mem_write(0xDEAD, 0xFF);
+        cpu.load(program).unwrap();
+        cpu.reset();
+        cpu.register_x = 0xAD;
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.register_a, 0xFF);
+    }
+
+    #[test]
+    fn lda_absolute_y() {
+        let mut cpu = CPU::new();
+        let program = vec![0xB9, 0x00, 0xDE, 0x00];
+        cpu.mem_write(0xDEAD, 0xFF);
+        cpu.load(program).unwrap();
+        cpu.reset();
+        cpu.register_y = 0xAD;
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.register_a, 0xFF);
+    }
+
+    #[test]
+    fn lda_indirect_x() {
+        let mut cpu = CPU::new();
+        let program = vec![0xA1, 0x02, 0x00];
+        cpu.mem_write_u16(0x04, 0x8086);
+        cpu.mem_write(0x8086, 0xFF);
+        cpu.load(program).unwrap();
+        cpu.reset();
+        cpu.register_x = 0x02;
+        cpu.run().unwrap();
+        //println!("{:?}", cpu.memory);
+
+        assert_eq!(cpu.register_a, 0xFF);
+    }
+
+    #[test]
     fn lda_indirect_y() {
         let mut cpu = CPU::new();
         let program = vec![0xB1, 0x02, 0x00];
         cpu.mem_write(0x8086, 0xFF);
         cpu.mem_write_u16(0x02, 0x8000);
-        cpu.load(program);
+        cpu.load(program).unwrap();
         cpu.reset();
         cpu.register_y = 0x86;
-        cpu.run();
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.register_a, 0xFF);
+    }
+
+    #[test]
+    fn tax_moves_a_to_x() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x0A, 0xAA, 0x00]).unwrap();
+
+        assert_eq!(cpu.register_x, 0x0A);
+    }
+
+    #[test]
+    fn inx_overflow() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA2, 0xFF, 0xE8, 0xE8, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn ldx_ldy_and_transfers() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA2, 0x11, 0xA0, 0x22, 0x8A, 0xA8, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_a, 0x11);
+        assert_eq!(cpu.register_x, 0x11);
+        assert_eq!(cpu.register_y, 0x11);
+    }
+
+    #[test]
+    fn sta_stx_sty() {
+        let mut cpu = CPU::new();
+        let program = vec![
+            0xA9, 0x01, 0xA2, 0x02, 0xA0, 0x03, 0x85, 0x10, 0x8E, 0x00, 0x02, 0x94, 0x20, 0x00,
+        ];
+        cpu.load_and_run(program).unwrap();
+
+        assert_eq!(cpu.mem_read(0x10), 0x01);
+        assert_eq!(cpu.mem_read(0x0200), 0x02);
+        assert_eq!(cpu.mem_read(0x22), 0x03);
+    }
+
+    #[test]
+    fn adc_sets_carry_and_overflow() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x50, 0x69, 0x50, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_a, 0xA0);
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW));
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+
+        cpu.load_and_run(vec![0xA9, 0xFF, 0x69, 0x02, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_a, 0x01);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(!cpu.status.contains(CpuFlags::OVERFLOW));
+    }
+
+    #[test]
+    fn sbc_borrows() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0x38, 0xA9, 0x05, 0xE9, 0x06, 0x00])
+            .unwrap();
 
         assert_eq!(cpu.register_a, 0xFF);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn nes_ignores_decimal_flag() {
+        let mut cpu = CPU::new();
+        // SED; CLC; LDA #$09; ADC #$01
+        cpu.load_and_run(vec![0xF8, 0x18, 0xA9, 0x09, 0x69, 0x01, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_a, 0x0A);
+    }
+
+    #[test]
+    fn adc_decimal() {
+        let mut cpu = CPU::new();
+        cpu.config = CpuConfig::mos6502();
+
+        // SED; CLC; LDA #$09; ADC #$01
+        cpu.load_and_run(vec![0xF8, 0x18, 0xA9, 0x09, 0x69, 0x01, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x10);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+
+        // 99 + 1: Z is set from the binary sum ($9A), N before the high digit is fixed up
+        cpu.load_and_run(vec![0xF8, 0x18, 0xA9, 0x99, 0x69, 0x01, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(!cpu.status.contains(CpuFlags::ZERO));
+        assert!(cpu.status.contains(CpuFlags::NEGATIVE));
+
+        // 50 + 50: V as if the digits were binary
+        cpu.load_and_run(vec![0xF8, 0x18, 0xA9, 0x50, 0x69, 0x50, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::OVERFLOW));
+    }
+
+    #[test]
+    fn sbc_decimal() {
+        let mut cpu = CPU::new();
+        cpu.config = CpuConfig::mos6502();
+
+        // SED; SEC; LDA #$10; SBC #$01
+        cpu.load_and_run(vec![0xF8, 0x38, 0xA9, 0x10, 0xE9, 0x01, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x09);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+
+        // 00 - 01 borrows
+        cpu.load_and_run(vec![0xF8, 0x38, 0xA9, 0x00, 0xE9, 0x01, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x99);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn arr_decimal() {
+        let mut cpu = CPU::new();
+        cpu.config = CpuConfig::mos6502();
+
+        // SED; CLC; LDA #$FF; ARR #$FF
+        cpu.load_and_run(vec![0xF8, 0x18, 0xA9, 0xFF, 0x6B, 0xFF, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0xD5);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(!cpu.status.contains(CpuFlags::OVERFLOW));
+    }
+
+    #[test]
+    fn logical_operations() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![
+            0xA9, 0b1100, 0x29, 0b1010, 0x09, 0b0001, 0x49, 0b1111, 0x00,
+        ])
+        .unwrap();
+
+        assert_eq!(cpu.register_a, 0b0110);
+    }
+
+    #[test]
+    fn shifts_and_rotates() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x81, 0x0A, 0x2A, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_a, 0x05);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+
+        cpu.load_and_run(vec![0xA9, 0x01, 0x4A, 0x6A, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(cpu.status.contains(CpuFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn inc_dec_memory() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x10, 0xFF);
+        cpu.mem_write(0x11, 0x00);
+        cpu.load_and_run(vec![0xE6, 0x10, 0xC6, 0x11, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.mem_read(0x10), 0x00);
+        assert_eq!(cpu.mem_read(0x11), 0xFF);
+    }
+
+    #[test]
+    fn cmp_sets_flags() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x10, 0xC9, 0x10, 0x00])
+            .unwrap();
+
+        assert!(cpu.status.contains(CpuFlags::ZERO | CpuFlags::CARRY));
+
+        cpu.load_and_run(vec![0xA2, 0x01, 0xE0, 0x02, 0x00])
+            .unwrap();
+
+        assert!(!cpu.status.intersects(CpuFlags::ZERO | CpuFlags::CARRY));
+        assert!(cpu.status.contains(CpuFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn bit_copies_high_bits() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x10, 0b1100_0000);
+        cpu.load_and_run(vec![0xA9, 0x01, 0x24, 0x10, 0x00])
+            .unwrap();
+
+        assert!(cpu
+            .status
+            .contains(CpuFlags::ZERO | CpuFlags::OVERFLOW | CpuFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn branch_loop() {
+        let mut cpu = CPU::new();
+        // LDX #$05; loop: DEX; BNE loop
+        cpu.load_and_run(vec![0xA2, 0x05, 0xCA, 0xD0, 0xFD, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_x, 0);
+        assert!(cpu.status.contains(CpuFlags::ZERO));
+    }
+
+    #[test]
+    fn jmp_absolute() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0x4C, 0x05, 0x80, 0xA9, 0x01, 0xA9, 0x02, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_a, 0x02);
+    }
+
+    #[test]
+    fn jmp_to_the_next_byte() {
+        let mut cpu = CPU::new();
+        // JMP $8001 lands on its own operand, which must not be skipped again
+        cpu.load(vec![0x4C, 0x01, 0x80]).unwrap();
+        cpu.reset();
+        cpu.step().unwrap();
+
+        assert_eq!(cpu.program_counter, 0x8001);
+    }
+
+    #[test]
+    fn branch_back_by_one() {
+        let mut cpu = CPU::new();
+        // SEC; BCS *-1 branches into its own offset byte
+        cpu.load(vec![0x38, 0xB0, 0xFF]).unwrap();
+        cpu.reset();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+
+        assert_eq!(cpu.program_counter, 0x8002);
+    }
+
+    #[test]
+    fn jsr_rts() {
+        let mut cpu = CPU::new();
+        // JSR sub; LDX #$01; BRK; sub: LDA #$42; RTS
+        let program = vec![0x20, 0x06, 0x80, 0xA2, 0x01, 0x00, 0xA9, 0x42, 0x60];
+        cpu.load_and_run(program).unwrap();
+
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.register_x, 0x01);
+        // only the three bytes pushed by the final BRK remain
+        assert_eq!(cpu.stack_pointer, STACK_RESET - 3);
+    }
+
+    #[test]
+    fn pha_pla() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x42, 0x48, 0xA9, 0x00, 0x68, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.stack_pointer, STACK_RESET - 3);
+    }
+
+    #[test]
+    fn php_plp_flags() {
+        let mut cpu = CPU::new();
+        // SEC; PHP; PLA; TAX; PHA; CLC; PLP
+        cpu.load_and_run(vec![0x38, 0x08, 0x68, 0xAA, 0x48, 0x18, 0x28, 0x00])
+            .unwrap();
+
+        assert_eq!(
+            CpuFlags::from_bits(cpu.register_x),
+            CpuFlags::CARRY | CpuFlags::INTERRUPT_DISABLE | CpuFlags::BREAK | CpuFlags::UNUSED
+        );
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(!cpu.status.contains(CpuFlags::BREAK));
+    }
+
+    #[test]
+    fn cycles_base() {
+        let mut cpu = CPU::new();
+        // LDA #$01 (2); STA $10 (3); BRK (7)
+        cpu.load_and_run(vec![0xA9, 0x01, 0x85, 0x10, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.cycles, 7 + 2 + 3 + 7);
+    }
+
+    #[test]
+    fn cycles_page_cross_on_indexed_read() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xBD, 0xFF, 0x10, 0x9D, 0xFF, 0x10, 0x00])
+            .unwrap();
+        cpu.reset();
+        cpu.register_x = 0x01;
+        cpu.run().unwrap();
+
+        // LDA abs,X pays for the page cross, STA abs,X always takes 5
+        assert_eq!(cpu.cycles, 7 + 5 + 5 + 7);
+    }
+
+    #[test]
+    fn cycles_indirect_y_page_cross() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(0x10, 0x20FF);
+        cpu.load(vec![0xB1, 0x10, 0x00]).unwrap();
+        cpu.reset();
+        cpu.register_y = 0x01;
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.cycles, 7 + 6 + 7);
+    }
+
+    #[test]
+    fn reset_keeps_counting_cycles() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xEA, 0x00]).unwrap();
+        assert_eq!(cpu.cycles, 7 + 2 + 7);
+
+        // a mid-run reset takes 7 more cycles rather than rewinding the count
+        cpu.reset();
+        assert_eq!(cpu.cycles, 7 + 2 + 7 + 7);
+    }
+
+    #[test]
+    fn cycles_branches() {
+        let mut cpu = CPU::new();
+        // BNE not taken (Z set by LDA #$00)
+        cpu.load_and_run(vec![0xA9, 0x00, 0xD0, 0x00, 0x00])
+            .unwrap();
+        assert_eq!(cpu.cycles, 7 + 2 + 2 + 7);
+
+        // BEQ taken, same page
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x00, 0xF0, 0x00, 0x00])
+            .unwrap();
+        assert_eq!(cpu.cycles, 7 + 2 + 3 + 7);
+
+        // BEQ taken backwards onto the previous page
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x7FF0, 0x00);
+        cpu.load_and_run(vec![0xA9, 0x00, 0xF0, 0xEC]).unwrap();
+        assert_eq!(cpu.cycles, 7 + 2 + 4 + 7);
+        cpu.stack_pop_status();
+        assert_eq!(cpu.stack_pop_u16(), 0x7FF2);
+    }
+
+    #[test]
+    fn reset_initialises_stack_pointer() {
+        let mut cpu = CPU::new();
+        cpu.stack_pointer = 0x12;
+        cpu.load(vec![0x00]).unwrap();
+        cpu.reset();
+
+        assert_eq!(cpu.stack_pointer, 0xFD);
+    }
+
+    #[test]
+    fn stack_wraps_within_page_one() {
+        let mut cpu = CPU::new();
+        cpu.stack_pointer = 0x00;
+        cpu.stack_push(0xAB);
+
+        assert_eq!(cpu.stack_pointer, 0xFF);
+        assert_eq!(cpu.mem_read(0x0100), 0xAB);
+        assert_eq!(cpu.stack_pop(), 0xAB);
+        assert_eq!(cpu.stack_pointer, 0x00);
+
+        cpu.stack_pointer = 0xFF;
+        cpu.mem_write(0x0100, 0xCD);
+        assert_eq!(cpu.stack_pop(), 0xCD);
+        assert_eq!(cpu.stack_pointer, 0x00);
+    }
+
+    #[test]
+    fn txs_tsx() {
+        let mut cpu = CPU::new();
+        // LDX #$80; TXS; LDX #$00; TSX
+        cpu.load_and_run(vec![0xA2, 0x80, 0x9A, 0xA2, 0x00, 0xBA, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_x, 0x80);
+        // BRK pushed three bytes below the new stack pointer
+        assert_eq!(cpu.stack_pointer, 0x80 - 3);
+        assert!(cpu.status.contains(CpuFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn pushed_status_break_bit() {
+        let mut cpu = CPU::new();
+        cpu.status = CpuFlags::CARRY;
+        cpu.stack_push_status(true);
+        cpu.stack_push_status(false);
+
+        assert_eq!(cpu.stack_pop(), 0b0010_0001);
+        assert_eq!(cpu.stack_pop(), 0b0011_0001);
+    }
+
+    #[test]
+    fn brk_pushes_state_and_jumps_through_vector() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(IRQ_VECTOR, 0x9000);
+        cpu.load_and_run(vec![0x38, 0x00, 0xFF]).unwrap();
+
+        assert_eq!(cpu.program_counter, 0x9000);
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        assert_eq!(cpu.stack_pop(), 0b0011_0101);
+        assert_eq!(cpu.stack_pop_u16(), 0x8003);
+    }
+
+    #[test]
+    fn rti_returns_from_brk() {
+        let mut cpu = CPU::new();
+        // handler: LDX #$42; RTI
+        cpu.mem_write(0x9000, 0xA2);
+        cpu.mem_write(0x9001, 0x42);
+        cpu.mem_write(0x9002, 0x40);
+        cpu.mem_write_u16(IRQ_VECTOR, 0x9000);
+        cpu.load(vec![0x00, 0xFF, 0xA9, 0x01, 0x00]).unwrap();
+        cpu.reset();
+        cpu.run().unwrap();
+        assert_eq!(cpu.program_counter, 0x9000);
+
+        // the second BRK comes after RTI resumed past the first one's padding byte
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_x, 0x42);
+        assert_eq!(cpu.register_a, 0x01);
+        assert_eq!(cpu.stack_pointer, STACK_RESET - 3);
+    }
+
+    #[test]
+    fn nmi_is_edge_triggered() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(NMI_VECTOR, 0x9000);
+        // handler: INX; BRK
+        cpu.mem_write(0x9000, 0xE8);
+        cpu.mem_write(0x9001, 0x00);
+        cpu.load(vec![0x00]).unwrap();
+        cpu.reset();
+
+        cpu.set_nmi_line(true);
+        cpu.set_nmi_line(true);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_x, 1);
+
+        // the line is still held active, so no new NMI
+        cpu.program_counter = 0x8000;
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_x, 1);
+
+        cpu.set_nmi_line(false);
+        cpu.set_nmi_line(true);
+        cpu.program_counter = 0x8000;
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn nmi_ignores_interrupt_disable() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(NMI_VECTOR, 0x9000);
+        cpu.mem_write(0x9000, 0x00);
+        cpu.load(vec![0xEA, 0x00]).unwrap();
+        cpu.reset();
+        cpu.set_nmi_line(true);
+        cpu.run().unwrap();
+
+        // NMI pushed with B clear, then the handler's BRK pushed on top of it
+        assert!(CpuFlags::from_bits(cpu.stack_pop()).contains(CpuFlags::BREAK));
+        assert_eq!(cpu.stack_pop_u16(), 0x9002);
+        assert!(!CpuFlags::from_bits(cpu.stack_pop()).contains(CpuFlags::BREAK));
+        assert_eq!(cpu.stack_pop_u16(), 0x8000);
+    }
+
+    #[test]
+    fn irq_honours_interrupt_disable() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(IRQ_VECTOR, 0x9000);
+        // handler: INY; BRK
+        cpu.mem_write(0x9000, 0xC8);
+        cpu.mem_write(0x9001, 0x00);
+        // NOP; NOP; CLI; NOP; BRK
+        cpu.load(vec![0xEA, 0xEA, 0x58, 0xEA, 0x00]).unwrap();
+        cpu.reset();
+        cpu.set_irq_line(true);
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.register_y, 1);
+        cpu.stack_pop_status();
+        assert_eq!(cpu.stack_pop_u16(), 0x9003);
+        cpu.stack_pop_status();
+        // CLI only takes effect after its own interrupt poll, so the NOP after it still runs
+        assert_eq!(cpu.stack_pop_u16(), 0x8004);
+        assert_eq!(cpu.cycles, 7 + 2 + 2 + 2 + 2 + 7 + 2 + 7);
+    }
+
+    #[test]
+    fn irq_slips_in_after_sei() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(IRQ_VECTOR, 0x9000);
+        cpu.mem_write(0x9000, 0xEA);
+        // SEI; NOP
+        cpu.load(vec![0x78, 0xEA]).unwrap();
+        cpu.reset();
+        cpu.status.remove(CpuFlags::INTERRUPT_DISABLE);
+
+        cpu.step().unwrap();
+        cpu.set_irq_line(true);
+        let step = cpu.step().unwrap();
+
+        assert_eq!(step.interrupt, Some(Interrupt::Irq));
+        assert_eq!(step.address, 0x9000);
+        cpu.stack_pop_status();
+        assert_eq!(cpu.stack_pop_u16(), 0x8001);
+    }
+
+    #[test]
+    fn runs_against_any_bus() {
+        struct Recording {
+            ram: Ram,
+            writes: Vec<(u16, u8)>,
+        }
+
+        impl Mem for Recording {
+            fn mem_read(&mut self, addr: u16) -> u8 {
+                self.ram.mem_read(addr)
+            }
+
+            fn mem_write(&mut self, addr: u16, data: u8) {
+                self.writes.push((addr, data));
+                self.ram.mem_write(addr, data);
+            }
+
+            fn mem_peek(&self, addr: u16) -> u8 {
+                self.ram.mem_peek(addr)
+            }
+        }
+
+        let mut cpu = CPU::with_bus(Recording {
+            ram: Ram::new(),
+            writes: Vec::new(),
+        });
+        cpu.load(vec![0xA9, 0x42, 0x8D, 0x00, 0x02, 0x00]).unwrap();
+        cpu.bus.writes.clear();
+        cpu.reset();
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.bus.writes[0], (0x0200, 0x42));
+    }
+
+    #[test]
+    fn full_address_space_includes_ffff() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0xFFFF, 0x12);
+        cpu.mem_write(0x0000, 0x34);
+
+        assert_eq!(cpu.mem_read(0xFFFF), 0x12);
+        // the high byte of a word at $FFFF comes from $0000
+        assert_eq!(cpu.mem_read_u16(0xFFFF), 0x3412);
+    }
+
+    #[test]
+    fn execution_straddles_ffff() {
+        let mut cpu = CPU::new();
+        // LDA #$42 with its operand in $FFFF, then INX at $0000
+        cpu.mem_write(0xFFFE, 0xA9);
+        cpu.mem_write(0xFFFF, 0x42);
+        cpu.mem_write(0x0000, 0xE8);
+        cpu.mem_write(0x0001, 0x00);
+        cpu.program_counter = 0xFFFE;
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.register_x, 0x01);
+    }
+
+    #[test]
+    fn absolute_operand_wraps_to_zero_page() {
+        let mut cpu = CPU::new();
+        // LDA $1234 with the opcode in $FFFF and the operand in $0000-$0001
+        cpu.mem_write(0xFFFF, 0xAD);
+        cpu.mem_write_u16(0x0000, 0x1234);
+        cpu.mem_write(0x0002, 0x00);
+        cpu.mem_write(0x1234, 0x99);
+        cpu.program_counter = 0xFFFF;
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.register_a, 0x99);
+        cpu.stack_pop_status();
+        assert_eq!(cpu.stack_pop_u16(), 0x0004);
+    }
+
+    #[test]
+    fn jsr_rts_across_ffff() {
+        let mut cpu = CPU::new();
+        // JSR $0200 at $FFFE, returning to $0001
+        cpu.mem_write(0xFFFE, 0x20);
+        cpu.mem_write_u16(0xFFFF, 0x0200);
+        cpu.mem_write(0x0200, 0x60);
+        cpu.mem_write(0x0001, 0xC8);
+        cpu.mem_write(0x0002, 0x00);
+        cpu.program_counter = 0xFFFE;
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.register_y, 0x01);
+    }
+
+    #[test]
+    fn jmp_indirect() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(0x0120, 0x8005);
+        cpu.load_and_run(vec![0x6C, 0x20, 0x01, 0xA9, 0x01, 0xA9, 0x02, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_a, 0x02);
+    }
+
+    #[test]
+    fn jmp_indirect_page_boundary_bug() {
+        let mut cpu = CPU::new();
+        // the pointer's high byte comes from $0200, not $0300
+        cpu.mem_write(0x02FF, 0x05);
+        cpu.mem_write(0x0200, 0x80);
+        cpu.mem_write(0x0300, 0x90);
+        cpu.load_and_run(vec![0x6C, 0xFF, 0x02, 0xA9, 0x01, 0xA9, 0x02, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_a, 0x02);
+    }
+
+    #[test]
+    fn unofficial_lax_sax() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x10, 0x5C);
+        // LAX $10; LDX #$0F; SAX $11
+        cpu.load_and_run(vec![0xA7, 0x10, 0xA2, 0x0F, 0x87, 0x11, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.register_a, 0x5C);
+        assert_eq!(cpu.mem_read(0x11), 0x0C);
+    }
+
+    #[test]
+    fn unofficial_read_modify_write() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x10, 0x43);
+        cpu.mem_write(0x11, 0x0F);
+        // LDA #$42; DCP $10; SEC; ISB $11
+        cpu.load_and_run(vec![0xA9, 0x42, 0xC7, 0x10, 0x38, 0xE7, 0x11, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.mem_read(0x10), 0x42);
+        assert_eq!(cpu.mem_read(0x11), 0x10);
+        assert_eq!(cpu.register_a, 0x32);
+
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x10, 0x81);
+        cpu.mem_write(0x11, 0x03);
+        // LDA #$01; SLO $10; CLC; RRA $11
+        cpu.load_and_run(vec![0xA9, 0x01, 0x07, 0x10, 0x18, 0x67, 0x11, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.mem_read(0x10), 0x02);
+        assert_eq!(cpu.mem_read(0x11), 0x01);
+        // (0x01 | 0x02) + 0x01 + carry out of the ROR
+        assert_eq!(cpu.register_a, 0x05);
+    }
+
+    #[test]
+    fn unofficial_immediates() {
+        let mut cpu = CPU::new();
+        // LDA #$FF; LDX #$0F; AXS #$05
+        cpu.load_and_run(vec![0xA9, 0xFF, 0xA2, 0x0F, 0xCB, 0x05, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_x, 0x0A);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+
+        // LDA #$FF; ANC #$80
+        cpu.load_and_run(vec![0xA9, 0xFF, 0x0B, 0x80, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+
+        // LDA #$FF; ALR #$03
+        cpu.load_and_run(vec![0xA9, 0xFF, 0x4B, 0x03, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x01);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+
+        // SEC; LDA #$FF; ARR #$C0
+        cpu.load_and_run(vec![0x38, 0xA9, 0xFF, 0x6B, 0xC0, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0xE0);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+        assert!(!cpu.status.contains(CpuFlags::OVERFLOW));
+
+        // SEC; LDA #$10; SBC #$01 through $EB
+        cpu.load_and_run(vec![0x38, 0xA9, 0x10, 0xEB, 0x01, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x0F);
+    }
+
+    #[test]
+    fn unofficial_nops_skip_operands() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![
+            0x1A, 0x80, 0xFF, 0x04, 0xFF, 0x1C, 0xFF, 0x10, 0xA9, 0x01, 0x00,
+        ])
+        .unwrap();
+        cpu.reset();
+        cpu.register_x = 0x01;
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.register_a, 0x01);
+        // NOP abs,X crossed into $1100 and paid for it
+        assert_eq!(cpu.cycles, 7 + 2 + 2 + 3 + 5 + 2 + 7);
+    }
+
+    #[test]
+    fn unofficial_opcodes_can_be_disabled() {
+        let mut cpu = CPU::new();
+        cpu.config.unofficial_opcodes = false;
+        cpu.mem_write(0x10, 0x5C);
+
+        assert_eq!(
+            cpu.load_and_run(vec![0xA9, 0x01, 0xA7, 0x10, 0x00]),
+            Err(CpuError::UnknownOpcode {
+                opcode: 0xA7,
+                program_counter: 0x8002
+            })
+        );
+        assert_eq!(cpu.register_x, 0x00);
+    }
+
+    #[test]
+    fn unknown_opcode_is_an_error_by_default() {
+        let mut cpu = CPU::new();
+
+        assert_eq!(
+            cpu.load_and_run(vec![0xE8, 0x02, 0xE8, 0x00]),
+            Err(CpuError::UnknownOpcode {
+                opcode: 0x02,
+                program_counter: 0x8001
+            })
+        );
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.program_counter, 0x8001);
+    }
+
+    #[test]
+    fn unknown_opcode_as_nop() {
+        let mut cpu = CPU::new();
+        cpu.config.unknown_opcodes = UnknownOpcodePolicy::Nop;
+        cpu.load_and_run(vec![0xE8, 0x02, 0xE8, 0x00]).unwrap();
+
+        assert_eq!(cpu.register_x, 2);
+        assert_eq!(cpu.cycles, 7 + 2 + 2 + 2 + 7);
+    }
+
+    #[test]
+    fn unknown_opcode_jams_until_reset() {
+        let mut cpu = CPU::new();
+        cpu.config.unknown_opcodes = UnknownOpcodePolicy::Jam;
+        let jammed = Err(CpuError::Jammed {
+            opcode: 0x02,
+            program_counter: 0x8001,
+        });
+
+        assert_eq!(cpu.load_and_run(vec![0xE8, 0x02, 0xE8, 0x00]), jammed);
+        assert_eq!(cpu.run(), jammed);
+        assert_eq!(cpu.register_x, 1);
+
+        // NMI can't get a jammed CPU going again, only reset can
+        cpu.set_nmi_line(true);
+        assert_eq!(cpu.run(), jammed);
+        cpu.reset();
+        cpu.mem_write(0x8001, 0xEA);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn load_rejects_oversized_programs() {
+        let mut cpu = CPU::new();
+
+        assert_eq!(
+            cpu.load(vec![0xEA; 0x8001]),
+            Err(CpuError::ProgramTooLarge {
+                len: 0x8001,
+                max: 0x8000
+            })
+        );
+        assert!(cpu.load(vec![0xEA; 0x8000]).is_ok());
+    }
+
+    #[test]
+    fn operandless_mode_is_an_error() {
+        let mut cpu = CPU::new();
+
+        assert_eq!(
+            cpu.lda(&AddressingMode::NoneAddressing),
+            Err(CpuError::BadAddressingMode(AddressingMode::NoneAddressing))
+        );
+    }
+
+    #[test]
+    fn step_executes_one_instruction() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xA9, 0x05, 0xBD, 0xFF, 0x10, 0x00]).unwrap();
+        cpu.reset();
+        cpu.register_x = 1;
+
+        assert_eq!(
+            cpu.step(),
+            Ok(Step {
+                address: 0x8000,
+                opcode: 0xA9,
+                cycles: 2,
+                interrupt: None
+            })
+        );
+        assert_eq!(cpu.register_a, 0x05);
+        assert_eq!(cpu.program_counter, 0x8002);
+
+        let step = cpu.step().unwrap();
+        assert_eq!(step.address, 0x8002);
+        assert_eq!(step.cycles, 5);
+    }
+
+    #[test]
+    fn step_reports_interrupts() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(NMI_VECTOR, 0x9000);
+        cpu.mem_write(0x9000, 0xE8);
+        cpu.load(vec![0xEA]).unwrap();
+        cpu.reset();
+        cpu.set_nmi_line(true);
+
+        assert_eq!(
+            cpu.step(),
+            Ok(Step {
+                address: 0x9000,
+                opcode: 0xE8,
+                cycles: 7 + 2,
+                interrupt: Some(Interrupt::Nmi)
+            })
+        );
+        assert_eq!(cpu.step().unwrap().interrupt, None);
+    }
+
+    #[test]
+    fn step_does_not_stop_at_brk() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(IRQ_VECTOR, 0x9000);
+        cpu.load(vec![0x00]).unwrap();
+        cpu.reset();
+
+        assert_eq!(cpu.step().unwrap().cycles, 7);
+        assert_eq!(cpu.program_counter, 0x9000);
+    }
+
+    #[test]
+    fn boots_through_the_cartridge_reset_vector() {
+        let mut prg_rom = vec![0; 0x4000];
+        // $C000: LDA #$42
+        prg_rom[0x0000] = 0xA9;
+        prg_rom[0x0001] = 0x42;
+        prg_rom[0x3FFC] = 0x00;
+        prg_rom[0x3FFD] = 0xC0;
+
+        let mut cpu = CPU::from_rom(crate::cartridge::test::test_rom(prg_rom)).unwrap();
+        assert_eq!(cpu.program_counter, 0xC000);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_a, 0x42);
+    }
+
+    #[test]
+    fn read_modify_write_writes_twice() {
+        use crate::single_step::{Access, BusAccess, RecordingBus};
+
+        let mut cpu = CPU::with_bus(RecordingBus::new());
+        // INC $10
+        cpu.mem_write(0x0200, 0xE6);
+        cpu.mem_write(0x0201, 0x10);
+        cpu.mem_write(0x0010, 0x41);
+        cpu.program_counter = 0x0200;
+        cpu.bus.log.clear();
+        cpu.step().unwrap();
+
+        let writes: Vec<BusAccess> = cpu
+            .bus
+            .log
+            .into_iter()
+            .filter(|access| access.access == Access::Write)
+            .collect();
+        let write = |value| BusAccess {
+            addr: 0x0010,
+            value,
+            access: Access::Write,
+        };
+        assert_eq!(writes, vec![write(0x41), write(0x42)]);
+    }
+
+    #[test]
+    fn run_for_cycles_runs_whole_instructions() {
+        let mut cpu = CPU::new();
+        // loop: INX; JMP loop
+        cpu.load(vec![0xE8, 0x4C, 0x00, 0x80]).unwrap();
+        cpu.reset();
+
+        assert_eq!(cpu.run_for_cycles(10), Ok(10));
+        assert_eq!(cpu.register_x, 2);
+        // INX (2) takes it to 12
+        assert_eq!(cpu.run_for_cycles(1), Ok(2));
+        assert_eq!(cpu.register_x, 3);
+    }
+
+    #[test]
+    fn run_until_predicate() {
+        let mut cpu = CPU::new();
+        // loop: INX; JMP loop
+        cpu.load(vec![0xE8, 0x4C, 0x00, 0x80]).unwrap();
+        cpu.reset();
+        cpu.run_until(|cpu| cpu.register_x == 5).unwrap();
+
+        assert_eq!(cpu.register_x, 5);
+        assert_eq!(cpu.program_counter, 0x8001);
+    }
+
+    #[test]
+    fn callback_sees_every_instruction() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xA9, 0x01, 0xAA, 0xE8, 0x00]).unwrap();
+        cpu.reset();
+
+        let mut trace = Vec::new();
+        cpu.run_with_callback(|cpu| trace.push(cpu.program_counter))
+            .unwrap();
+
+        assert_eq!(trace, vec![0x8000, 0x8002, 0x8003, 0x8004]);
+    }
+
+    #[test]
+    fn callback_can_mutate_state() {
+        let mut cpu = CPU::new();
+        // LDA $FE; BEQ loop
+        cpu.load(vec![0xA5, 0xFE, 0xF0, 0xFC, 0x00]).unwrap();
+        cpu.reset();
+
+        let mut polls = 0;
+        cpu.run_with_callback(|cpu| {
+            polls += 1;
+            if polls == 5 {
+                cpu.mem_write(0xFE, 0x77);
+            }
+        })
+        .unwrap();
+
+        assert_eq!(cpu.register_a, 0x77);
+    }
+
+    #[test]
+    fn run_until_trap_stops_at_jump_to_self() {
+        let mut cpu = CPU::new();
+        // LDX #$03; loop: DEX; BNE loop; trap: JMP trap
+        cpu.load(vec![0xA2, 0x03, 0xCA, 0xD0, 0xFD, 0x4C, 0x05, 0x80])
+            .unwrap();
+        cpu.reset();
+
+        assert_eq!(cpu.run_until_trap(), Ok(0x8005));
+        assert_eq!(cpu.register_x, 0);
+    }
+
+    #[test]
+    fn run_until_trap_stops_at_branch_to_self() {
+        let mut cpu = CPU::new();
+        // SEC; trap: BCS trap
+        cpu.load(vec![0x38, 0xB0, 0xFE]).unwrap();
+        cpu.reset();
+
+        assert_eq!(cpu.run_until_trap(), Ok(0x8001));
+    }
+
+    /// Runs Klaus Dormann's 6502_functional_test.bin, a 64K image that starts at $0400 and
+    /// traps at $3469 once every test has passed; any other trap is the failing test.
+    #[test]
+    #[ignore = "long-running, needs test_roms/6502_functional_test.bin"]
+    fn klaus_dormann_functional_test() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test_roms")
+            .join("6502_functional_test.bin");
+        let image = std::fs::read(path).expect("test_roms/6502_functional_test.bin");
+        assert_eq!(image.len(), 0x10000);
+
+        let mut ram = Ram::new();
+        for (addr, byte) in image.iter().enumerate() {
+            ram.mem_write(addr as u16, *byte);
+        }
+        let mut cpu = CPU::with_bus(ram);
+        // the stock binary also checks decimal mode
+        cpu.config = CpuConfig::mos6502();
+        cpu.reset();
+        cpu.program_counter = 0x0400;
+
+        let trap = cpu.run_until_trap().unwrap();
+        assert_eq!(
+            trap,
+            0x3469,
+            "trapped at ${:04X}, test case {:#04x}",
+            trap,
+            cpu.mem_read(0x0200)
+        );
     }
 }
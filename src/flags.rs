@@ -0,0 +1,154 @@
+#![allow(dead_code)]
+
+use std::fmt;
+use std::ops::{BitOr, BitOrAssign};
+
+/// The 6502 processor status register.
+///
+///  7 6 5 4 3 2 1 0
+///  N V _ B D I Z C
+///  | | | | | | | +--- Carry
+///  | | | | | | +----- Zero
+///  | | | | | +------- Interrupt Disable
+///  | | | | +--------- Decimal Mode (ignored by the NES's 2A03)
+///  | | | +----------- Break
+///  | | +------------- Unused, always reads as 1
+///  | +--------------- Overflow
+///  +----------------- Negative
+///
+/// B and the unused bit have no latch in the CPU; they only appear in the copy of the
+/// register pushed onto the stack, see [`CpuFlags::to_stack_byte`].
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuFlags(u8);
+
+impl CpuFlags {
+    pub const CARRY: CpuFlags = CpuFlags(0b0000_0001);
+    pub const ZERO: CpuFlags = CpuFlags(0b0000_0010);
+    pub const INTERRUPT_DISABLE: CpuFlags = CpuFlags(0b0000_0100);
+    pub const DECIMAL_MODE: CpuFlags = CpuFlags(0b0000_1000);
+    pub const BREAK: CpuFlags = CpuFlags(0b0001_0000);
+    pub const UNUSED: CpuFlags = CpuFlags(0b0010_0000);
+    pub const OVERFLOW: CpuFlags = CpuFlags(0b0100_0000);
+    pub const NEGATIVE: CpuFlags = CpuFlags(0b1000_0000);
+
+    pub const fn empty() -> Self {
+        CpuFlags(0)
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        CpuFlags(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// True if every flag in `other` is set.
+    pub const fn contains(self, other: CpuFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// True if any flag in `other` is set.
+    pub const fn intersects(self, other: CpuFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn insert(&mut self, other: CpuFlags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: CpuFlags) {
+        self.0 &= !other.0;
+    }
+
+    pub fn set(&mut self, other: CpuFlags, value: bool) {
+        if value {
+            self.insert(other);
+        } else {
+            self.remove(other);
+        }
+    }
+
+    /// The byte pushed onto the stack. PHP and BRK push B set, NMI and IRQ push it clear;
+    /// the unused bit is always pushed set.
+    pub fn to_stack_byte(self, break_flag: bool) -> u8 {
+        let mut flags = self | CpuFlags::UNUSED;
+        flags.set(CpuFlags::BREAK, break_flag);
+        flags.0
+    }
+
+    /// The register restored by PLP and RTI, which ignore B and the unused bit in `byte`.
+    pub fn from_stack_byte(byte: u8) -> Self {
+        let mut flags = CpuFlags(byte);
+        flags.remove(CpuFlags::BREAK);
+        flags.insert(CpuFlags::UNUSED);
+        flags
+    }
+}
+
+impl BitOr for CpuFlags {
+    type Output = CpuFlags;
+
+    fn bitor(self, rhs: CpuFlags) -> CpuFlags {
+        CpuFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for CpuFlags {
+    fn bitor_assign(&mut self, rhs: CpuFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl fmt::Debug for CpuFlags {
+    /// Prints the flags as `NV-BDIZC`, upper case when set.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = "NV-BDIZC";
+        let flags: String = names
+            .chars()
+            .enumerate()
+            .map(|(i, c)| {
+                if self.0 & (0x80 >> i) != 0 {
+                    c
+                } else {
+                    c.to_ascii_lowercase()
+                }
+            })
+            .collect();
+        write!(f, "CpuFlags({:#04x} {})", self.0, flags)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_and_remove() {
+        let mut flags = CpuFlags::empty();
+        flags.set(CpuFlags::CARRY | CpuFlags::ZERO, true);
+        assert!(flags.contains(CpuFlags::CARRY));
+        assert!(flags.contains(CpuFlags::CARRY | CpuFlags::ZERO));
+        assert!(!flags.contains(CpuFlags::CARRY | CpuFlags::NEGATIVE));
+        assert!(flags.intersects(CpuFlags::CARRY | CpuFlags::NEGATIVE));
+
+        flags.set(CpuFlags::CARRY, false);
+        assert_eq!(flags.bits(), 0b0000_0010);
+    }
+
+    #[test]
+    fn stack_byte_round_trip() {
+        let flags = CpuFlags::CARRY | CpuFlags::NEGATIVE;
+        assert_eq!(flags.to_stack_byte(true), 0b1011_0001);
+        assert_eq!(flags.to_stack_byte(false), 0b1010_0001);
+
+        let pulled = CpuFlags::from_stack_byte(0b1001_0001);
+        assert_eq!(pulled, flags | CpuFlags::UNUSED);
+    }
+
+    #[test]
+    fn debug_lists_flags() {
+        let flags = CpuFlags::NEGATIVE | CpuFlags::UNUSED | CpuFlags::CARRY;
+        assert_eq!(format!("{:?}", flags), "CpuFlags(0xa1 Nv-bdizC)");
+    }
+}
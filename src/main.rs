@@ -1,4 +1,12 @@
+mod bus;
+mod cartridge;
 mod cpu;
+mod flags;
+mod mapper;
+mod opcodes;
+#[cfg(test)]
+mod single_step;
+mod trace;
 
 fn main() {
     println!("Hello, world!");
@@ -0,0 +1,131 @@
+use super::Mapper;
+use crate::cartridge::{Mirroring, Rom};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+
+/// Mapper 7: AxROM. Writes to $8000-$FFFF pick a 32KB PRG bank with bits 0-2 and which 1KB of
+/// VRAM every nametable shows with bit 4. CHR is 8KB of RAM.
+///
+/// Only AMROM has bus conflicts, and the later ANROM/AOROM boards most games shipped on
+/// don't, so the written value is ANDed with the ROM only for NES 2.0 submapper 2.
+#[derive(Debug)]
+pub struct AxRom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    bus_conflicts: bool,
+    prg_bank: usize,
+    mirroring: Mirroring,
+}
+
+impl AxRom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = super::chr(&rom);
+        AxRom {
+            bus_conflicts: rom.submapper == 2,
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            prg_bank: 0,
+            mirroring: Mirroring::SingleScreenLower,
+        }
+    }
+
+    fn prg_rom_index(&self, addr: u16) -> usize {
+        (self.prg_bank * PRG_BANK_SIZE + (addr - 0x8000) as usize) % self.prg_rom.len()
+    }
+}
+
+impl Mapper for AxRom {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_index(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        let data = if self.bus_conflicts {
+            data & self.prg_rom[self.prg_rom_index(addr)]
+        } else {
+            data
+        };
+        self.prg_bank = (data & 0b0111) as usize;
+        self.mirroring = if data & 0b1_0000 == 0 {
+            Mirroring::SingleScreenLower
+        } else {
+            Mirroring::SingleScreenUpper
+        };
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        super::chr_read(&self.chr, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        super::chr_write(&mut self.chr, self.chr_is_ram, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::mapper::test::numbered_banks;
+
+    /// 128KB of PRG-ROM, each 32KB bank filled with its own number.
+    fn axrom(submapper: u8) -> AxRom {
+        let mut rom = test_rom(numbered_banks(4, PRG_BANK_SIZE));
+        rom.chr_rom = vec![];
+        rom.submapper = submapper;
+        AxRom::new(rom)
+    }
+
+    #[test]
+    fn switches_32k_bank() {
+        let mut axrom = axrom(0);
+        assert_eq!(axrom.cpu_read(0x8000), Some(0));
+
+        axrom.cpu_write(0x8000, 2);
+        assert_eq!(axrom.cpu_read(0x8000), Some(2));
+        assert_eq!(axrom.cpu_read(0xFFFF), Some(2));
+
+        // 4 banks: bank 5 wraps to 1
+        axrom.cpu_write(0x8000, 5);
+        assert_eq!(axrom.cpu_read(0xC000), Some(1));
+    }
+
+    #[test]
+    fn single_screen_mirroring() {
+        let mut axrom = axrom(0);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+
+        axrom.cpu_write(0x8000, 0b1_0000);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenUpper);
+        axrom.cpu_write(0x8000, 0b0_0000);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+    }
+
+    #[test]
+    fn bus_conflicts_only_on_submapper_2() {
+        let mut axrom = axrom(2);
+        // bank 0 is all zeroes
+        axrom.cpu_write(0x8000, 0b1_0011);
+        assert_eq!(axrom.cpu_read(0x8000), Some(0));
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+    }
+
+    #[test]
+    fn chr_ram() {
+        let mut axrom = axrom(0);
+        axrom.ppu_write(0x0042, 0x24);
+        assert_eq!(axrom.ppu_read(0x0042), 0x24);
+    }
+}
@@ -0,0 +1,132 @@
+use super::Mapper;
+use crate::cartridge::{Mirroring, Rom};
+
+const CHR_BANK_SIZE: usize = 0x2000;
+
+/// Mapper 3: CNROM. PRG-ROM is laid out as on NROM; writes to $8000-$FFFF pick the 8KB CHR
+/// bank.
+///
+/// Like UxROM, the written value is ANDed with the ROM byte at that address unless NES 2.0
+/// submapper 1 says the board avoids the bus conflict.
+#[derive(Debug)]
+pub struct CnRom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    chr_bank: usize,
+}
+
+impl CnRom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = super::chr(&rom);
+        CnRom {
+            bus_conflicts: rom.submapper != 1,
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            mirroring: rom.screen_mirroring,
+            chr_bank: 0,
+        }
+    }
+
+    fn prg_rom_index(&self, addr: u16) -> usize {
+        (addr - 0x8000) as usize % self.prg_rom.len()
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        (self.chr_bank * CHR_BANK_SIZE + (addr & 0x1FFF) as usize) % self.chr.len()
+    }
+}
+
+impl Mapper for CnRom {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_index(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        let data = if self.bus_conflicts {
+            data & self.prg_rom[self.prg_rom_index(addr)]
+        } else {
+            data
+        };
+        let bank_count = (self.chr.len() / CHR_BANK_SIZE).max(1);
+        self.chr_bank = data as usize % bank_count;
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_index(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_index(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::mapper::test::numbered_banks;
+
+    /// 32KB of PRG-ROM filled with $FF and 32KB of CHR-ROM, each bank filled with its number.
+    fn cnrom(submapper: u8) -> CnRom {
+        let mut prg_rom = vec![0xFF; 0x8000];
+        prg_rom[0] = 0x01;
+        let mut rom = test_rom(prg_rom);
+        rom.chr_rom = numbered_banks(4, CHR_BANK_SIZE);
+        rom.submapper = submapper;
+        rom.screen_mirroring = Mirroring::Horizontal;
+        CnRom::new(rom)
+    }
+
+    #[test]
+    fn switches_chr_bank() {
+        let mut cnrom = cnrom(0);
+        assert_eq!(cnrom.ppu_read(0x0000), 0);
+
+        cnrom.cpu_write(0xFFFF, 2);
+        assert_eq!(cnrom.ppu_read(0x0000), 2);
+        assert_eq!(cnrom.ppu_read(0x1FFF), 2);
+
+        // CHR-ROM can't be written
+        cnrom.ppu_write(0x0000, 0x42);
+        assert_eq!(cnrom.ppu_read(0x0000), 2);
+        assert_eq!(cnrom.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn prg_rom_is_fixed() {
+        let mut cnrom = cnrom(0);
+        cnrom.cpu_write(0x8001, 3);
+
+        assert_eq!(cnrom.cpu_read(0x8000), Some(0x01));
+        assert_eq!(cnrom.cpu_read(0xFFFF), Some(0xFF));
+    }
+
+    #[test]
+    fn bus_conflicts() {
+        let mut conflicting = cnrom(0);
+        // $8000 holds 1
+        conflicting.cpu_write(0x8000, 3);
+        assert_eq!(conflicting.ppu_read(0x0000), 1);
+
+        let mut clean = cnrom(1);
+        clean.cpu_write(0x8000, 3);
+        assert_eq!(clean.ppu_read(0x0000), 3);
+    }
+}
@@ -0,0 +1,418 @@
+use super::Mapper;
+use crate::cartridge::{Mirroring, Rom};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+/// Mapper 1: Nintendo's MMC1 (SxROM boards).
+///
+/// The CPU loads its registers one bit at a time through a 5-bit shift register: each write
+/// to $8000-$FFFF shifts bit 0 of the data in, and the fifth write copies the result into the
+/// register picked by address bits 13-14 of that write. A write with bit 7 set clears the
+/// shift register instead and switches to PRG mode 3.
+///
+/// ```text
+/// $8000-$9FFF  Control   4bit0
+///                        -----
+///                        CPPMM
+///                        |||++- Mirroring: one-screen lower, one-screen upper, vertical,
+///                        |||               horizontal
+///                        |++--- PRG mode: 0/1 32KB at $8000, 2 first bank fixed at $8000,
+///                        |                3 last bank fixed at $C000
+///                        +----- CHR mode: 0 one 8KB bank, 1 two 4KB banks
+/// $A000-$BFFF  CHR bank 0 (4KB units; bit 4 picks the 256KB PRG half on 512KB boards)
+/// $C000-$DFFF  CHR bank 1
+/// $E000-$FFFF  PRG bank  RPPPP: 16KB bank, R = 1 disables PRG-RAM
+/// ```
+#[derive(Debug)]
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    /// How much of the start of `prg_ram` is battery-backed.
+    prg_nvram_size: usize,
+
+    shift_register: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+
+    accesses: u64,
+    last_write_access: Option<u64>,
+}
+
+/// The shift register starts out holding just this marker bit; it has been shifted down to
+/// bit 0 when the fifth write arrives.
+const SHIFT_REGISTER_RESET: u8 = 0b1_0000;
+
+impl Mmc1 {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = super::chr(&rom);
+        Mmc1 {
+            prg_ram: super::prg_ram(&rom),
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            prg_nvram_size: if rom.battery { rom.prg_nvram_size } else { 0 },
+            shift_register: SHIFT_REGISTER_RESET,
+            control: 0b0_1100,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            accesses: 0,
+            last_write_access: None,
+        }
+    }
+
+    fn write_serial(&mut self, addr: u16, data: u8) {
+        // the MMC1 only sees the first of two writes on back-to-back cycles, like the
+        // double write of a read-modify-write instruction; see `Mapper::cpu_clock` for why
+        // adjacent bus accesses stand in for adjacent cycles
+        let consecutive = self.last_write_access == Some(self.accesses.wrapping_sub(1));
+        self.last_write_access = Some(self.accesses);
+        if consecutive {
+            return;
+        }
+
+        if data & 0b1000_0000 != 0 {
+            self.shift_register = SHIFT_REGISTER_RESET;
+            self.control |= 0b0_1100;
+            return;
+        }
+
+        let complete = self.shift_register & 1 != 0;
+        self.shift_register = (self.shift_register >> 1) | ((data & 1) << 4);
+        if complete {
+            let value = self.shift_register;
+            match addr {
+                0x8000..=0x9FFF => self.control = value,
+                0xA000..=0xBFFF => self.chr_bank_0 = value,
+                0xC000..=0xDFFF => self.chr_bank_1 = value,
+                _ => self.prg_bank = value,
+            }
+            self.shift_register = SHIFT_REGISTER_RESET;
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0b1_0000 == 0
+    }
+
+    fn prg_rom_index(&self, addr: u16) -> usize {
+        let bank = (self.prg_bank & 0b1111) as usize;
+        let (low, high) = match (self.control >> 2) & 0b11 {
+            0 | 1 => (bank & !1, bank | 1),
+            2 => (0, bank),
+            _ => (bank, 0b1111),
+        };
+        // 512KB boards use CHR bank 0's top bit to pick which 256KB the banks above are in
+        let outer = if self.prg_rom.len() > 16 * PRG_BANK_SIZE {
+            (self.chr_bank_0 & 0b1_0000) as usize
+        } else {
+            0
+        };
+
+        let bank = if addr < 0xC000 { low } else { high };
+        // NES 2.0 headers can declare less than one bank, which is mirrored to fill it
+        let bank_count = (self.prg_rom.len() / PRG_BANK_SIZE).max(1);
+        let index = ((outer + bank) % bank_count) * PRG_BANK_SIZE;
+        (index + (addr as usize & (PRG_BANK_SIZE - 1))) % self.prg_rom.len()
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        let addr = addr as usize & 0x1FFF;
+        let index = if self.control & 0b1_0000 == 0 {
+            (self.chr_bank_0 & 0b1_1110) as usize * CHR_BANK_SIZE + addr
+        } else {
+            let bank = if addr < CHR_BANK_SIZE {
+                self.chr_bank_0
+            } else {
+                self.chr_bank_1
+            };
+            bank as usize * CHR_BANK_SIZE + (addr & (CHR_BANK_SIZE - 1))
+        };
+        index % self.chr.len()
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_clock(&mut self) {
+        self.accesses = self.accesses.wrapping_add(1);
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                super::prg_ram_index(&self.prg_ram, addr).map(|i| self.prg_ram[i])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_index(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                if let Some(i) = super::prg_ram_index(&self.prg_ram, addr) {
+                    self.prg_ram[i] = data;
+                }
+            }
+            0x8000..=0xFFFF => self.write_serial(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_index(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_index(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        (self.prg_nvram_size > 0).then(|| &self.prg_ram[..self.prg_nvram_size])
+    }
+
+    fn load_save_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_nvram_size);
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::mapper::test::numbered_banks;
+
+    /// 128KB of PRG-ROM and 32KB of CHR-ROM, each bank filled with its own number.
+    fn mmc1() -> Mmc1 {
+        let mut rom = test_rom(numbered_banks(8, PRG_BANK_SIZE));
+        rom.chr_rom = numbered_banks(8, CHR_BANK_SIZE);
+        Mmc1::new(rom)
+    }
+
+    /// A write as an STA absolute makes it: three reads, then the write.
+    fn sta(mmc1: &mut Mmc1, addr: u16, data: u8) {
+        for _ in 0..4 {
+            mmc1.cpu_clock();
+        }
+        mmc1.cpu_write(addr, data);
+    }
+
+    /// Five serial writes, least significant bit first.
+    fn write_register(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        for i in 0..5 {
+            sta(mmc1, addr, value >> i);
+        }
+    }
+
+    #[test]
+    fn powers_on_with_last_bank_fixed() {
+        let mut mmc1 = mmc1();
+
+        assert_eq!(mmc1.cpu_read(0x8000), Some(0));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(7));
+        assert_eq!(mmc1.cpu_read(0xFFFF), Some(7));
+    }
+
+    #[test]
+    fn register_loads_after_five_writes() {
+        let mut mmc1 = mmc1();
+        for i in 0..4 {
+            sta(&mut mmc1, 0xE000, 3 >> i);
+            assert_eq!(mmc1.cpu_read(0x8000), Some(0));
+        }
+        sta(&mut mmc1, 0xE000, 0);
+
+        assert_eq!(mmc1.cpu_read(0x8000), Some(3));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(7));
+    }
+
+    #[test]
+    fn fifth_write_address_picks_the_register() {
+        let mut mmc1 = mmc1();
+        for _ in 0..4 {
+            sta(&mut mmc1, 0xA000, 1);
+        }
+        sta(&mut mmc1, 0xF000, 0);
+
+        // loaded 0b01111 into the PRG bank, not CHR bank 0
+        assert_eq!(mmc1.cpu_read(0x8000), Some(7));
+        assert_eq!(mmc1.ppu_read(0x0000), 0);
+    }
+
+    #[test]
+    fn bit_7_resets_the_shift_register() {
+        let mut mmc1 = mmc1();
+        write_register(&mut mmc1, 0x8000, 0b0_0010);
+        sta(&mut mmc1, 0xE000, 1);
+        sta(&mut mmc1, 0xE000, 1);
+        sta(&mut mmc1, 0x8000, 0x80);
+        write_register(&mut mmc1, 0xE000, 2);
+
+        assert_eq!(mmc1.cpu_read(0x8000), Some(2));
+        // and back to PRG mode 3
+        assert_eq!(mmc1.cpu_read(0xC000), Some(7));
+        assert_eq!(mmc1.mirroring(), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn consecutive_writes_are_ignored() {
+        let mut mmc1 = mmc1();
+        for i in 0..5 {
+            // INC $E000 writes the old value and then the new one on the next cycle
+            for _ in 0..5 {
+                mmc1.cpu_clock();
+            }
+            mmc1.cpu_write(0xE000, 5 >> i);
+            mmc1.cpu_clock();
+            mmc1.cpu_write(0xE000, 0xFF);
+        }
+
+        assert_eq!(mmc1.cpu_read(0x8000), Some(5));
+    }
+
+    #[test]
+    fn prg_modes() {
+        let mut mmc1 = mmc1();
+        write_register(&mut mmc1, 0xE000, 5);
+
+        // 32KB: the low bit of the bank is ignored
+        write_register(&mut mmc1, 0x8000, 0b0_0000);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(4));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(5));
+
+        // first bank fixed at $8000
+        write_register(&mut mmc1, 0x8000, 0b0_1000);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(0));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(5));
+
+        // last bank fixed at $C000
+        write_register(&mut mmc1, 0x8000, 0b0_1100);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(5));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(7));
+    }
+
+    #[test]
+    fn chr_modes() {
+        let mut mmc1 = mmc1();
+        write_register(&mut mmc1, 0xA000, 3);
+        write_register(&mut mmc1, 0xC000, 6);
+
+        // 8KB: CHR bank 0 without its low bit
+        assert_eq!(mmc1.ppu_read(0x0000), 2);
+        assert_eq!(mmc1.ppu_read(0x1000), 3);
+
+        write_register(&mut mmc1, 0x8000, 0b1_1100);
+        assert_eq!(mmc1.ppu_read(0x0000), 3);
+        assert_eq!(mmc1.ppu_read(0x1FFF), 6);
+    }
+
+    #[test]
+    fn switchable_mirroring() {
+        let mut mmc1 = mmc1();
+        let modes = [
+            Mirroring::SingleScreenLower,
+            Mirroring::SingleScreenUpper,
+            Mirroring::Vertical,
+            Mirroring::Horizontal,
+        ];
+        for (bits, mirroring) in modes.into_iter().enumerate() {
+            write_register(&mut mmc1, 0x8000, 0b0_1100 | bits as u8);
+            assert_eq!(mmc1.mirroring(), mirroring);
+        }
+    }
+
+    #[test]
+    fn prg_ram_can_be_disabled() {
+        let mut mmc1 = mmc1();
+        mmc1.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc1.cpu_read(0x6000), Some(0x42));
+
+        write_register(&mut mmc1, 0xE000, 0b1_0000);
+        assert_eq!(mmc1.cpu_read(0x6000), None);
+        mmc1.cpu_write(0x6000, 0x00);
+
+        write_register(&mut mmc1, 0xE000, 0b0_0000);
+        assert_eq!(mmc1.cpu_read(0x6000), Some(0x42));
+    }
+
+    #[test]
+    fn prg_rom_smaller_than_a_bank() {
+        // as a NES 2.0 header with an exponent-multiplier size can declare
+        let mut rom = test_rom(vec![0; PRG_BANK_SIZE]);
+        rom.prg_rom = vec![0; 0x2000];
+        rom.prg_rom[0] = 0xAA;
+        let mut mmc1 = Mmc1::new(rom);
+
+        assert_eq!(mmc1.cpu_read(0x8000), Some(0xAA));
+        assert_eq!(mmc1.cpu_read(0xA000), Some(0xAA));
+        assert_eq!(mmc1.cpu_read(0xE000), Some(0xAA));
+    }
+
+    #[test]
+    fn battery_backed_prg_ram() {
+        assert_eq!(mmc1().save_ram(), None);
+
+        let mut rom = test_rom(vec![0; 2 * PRG_BANK_SIZE]);
+        rom.battery = true;
+        rom.prg_nvram_size = 0x2000;
+        rom.prg_ram_size = 0;
+        let mut mmc1 = Mmc1::new(rom);
+        mmc1.load_save_ram(&[1, 2, 3]);
+        mmc1.cpu_write(0x6003, 4);
+
+        assert_eq!(mmc1.cpu_read(0x6001), Some(2));
+        assert_eq!(&mmc1.save_ram().unwrap()[..4], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn only_the_battery_backed_part_is_saved() {
+        let mut rom = test_rom(vec![0; 2 * PRG_BANK_SIZE]);
+        rom.battery = true;
+        rom.prg_nvram_size = 0x800;
+        rom.prg_ram_size = 0x1000;
+        let mut mmc1 = Mmc1::new(rom);
+        mmc1.load_save_ram(&[7; 0x1800]);
+
+        assert_eq!(mmc1.save_ram(), Some(&[7; 0x800][..]));
+        assert_eq!(mmc1.cpu_read(0x6800), Some(0));
+    }
+
+    #[test]
+    fn chr_ram() {
+        let mut rom = test_rom(vec![0; 2 * PRG_BANK_SIZE]);
+        rom.chr_rom = vec![];
+        let mut mmc1 = Mmc1::new(rom);
+        mmc1.ppu_write(0x1234, 0x56);
+
+        assert_eq!(mmc1.ppu_read(0x1234), 0x56);
+    }
+
+    #[test]
+    fn prg_rom_512k_outer_bank() {
+        let mut mmc1 = Mmc1::new(test_rom(numbered_banks(32, PRG_BANK_SIZE)));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(15));
+
+        write_register(&mut mmc1, 0xA000, 0b1_0000);
+        write_register(&mut mmc1, 0xE000, 2);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(18));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(31));
+    }
+}
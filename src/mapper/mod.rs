@@ -0,0 +1,159 @@
+#![allow(dead_code)]
+
+mod axrom;
+mod cnrom;
+mod mmc1;
+mod nrom;
+mod uxrom;
+
+pub use axrom::AxRom;
+pub use cnrom::CnRom;
+pub use mmc1::Mmc1;
+pub use nrom::Nrom;
+pub use uxrom::UxRom;
+
+use std::fmt;
+
+use crate::cartridge::{Mirroring, Rom, RomError};
+
+/// The circuitry on a cartridge board: what the CPU sees at $4020-$FFFF, what the PPU sees at
+/// $0000-$1FFF, and how the console's 2KB of VRAM is arranged into nametables.
+///
+/// CPU reads return `None` where the board leaves the data bus floating, so the bus can
+/// supply open-bus instead.
+pub trait Mapper: fmt::Debug {
+    /// Called on every CPU bus access, before it is dispatched, for mappers that care which
+    /// accesses are back to back. The core doesn't make the 6502's dummy accesses, so this
+    /// isn't once per cycle; but two writes that a 6502 makes on consecutive cycles, like the
+    /// double write of a read-modify-write instruction, are still consecutive accesses, and
+    /// two writes from separate instructions never are.
+    fn cpu_clock(&mut self) {}
+
+    /// A CPU read, for boards where reading has side effects. The rest only implement
+    /// [`Mapper::cpu_peek`].
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    /// What [`Mapper::cpu_read`] would return, without its side effects.
+    fn cpu_peek(&self, addr: u16) -> Option<u8>;
+
+    fn cpu_write(&mut self, addr: u16, data: u8);
+
+    fn ppu_read(&mut self, addr: u16) -> u8;
+
+    fn ppu_write(&mut self, addr: u16, data: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    /// Battery-backed PRG-RAM to save between sessions, if the cartridge has any.
+    fn save_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Restores battery-backed PRG-RAM from an earlier [`Mapper::save_ram`].
+    fn load_save_ram(&mut self, _data: &[u8]) {}
+}
+
+/// Builds the mapper the cartridge's header asks for.
+pub fn for_rom(rom: Rom) -> Result<Box<dyn Mapper>, RomError> {
+    match rom.mapper {
+        0 => Ok(Box::new(Nrom::new(rom))),
+        1 => Ok(Box::new(Mmc1::new(rom))),
+        2 => Ok(Box::new(UxRom::new(rom))),
+        3 => Ok(Box::new(CnRom::new(rom))),
+        7 => Ok(Box::new(AxRom::new(rom))),
+        mapper => Err(RomError::UnsupportedMapper(mapper)),
+    }
+}
+
+/// PRG-RAM as the header describes it, battery-backed part first, with the trainer, if any,
+/// loaded at $7000.
+fn prg_ram(rom: &Rom) -> Vec<u8> {
+    let mut prg_ram = vec![0; rom.prg_ram_size + rom.prg_nvram_size];
+    if let Some(trainer) = &rom.trainer {
+        prg_ram.resize(prg_ram.len().max(0x2000), 0);
+        prg_ram[0x1000..0x1000 + trainer.len()].copy_from_slice(trainer);
+    }
+    prg_ram
+}
+
+/// The pattern table memory: CHR-ROM if the cartridge has any, otherwise CHR-RAM (8KB unless
+/// the header says otherwise). The flag is true for RAM.
+fn chr(rom: &Rom) -> (Vec<u8>, bool) {
+    if rom.chr_rom.is_empty() {
+        let size = rom.chr_ram_size + rom.chr_nvram_size;
+        (vec![0; if size == 0 { 0x2000 } else { size }], true)
+    } else {
+        (rom.chr_rom.clone(), false)
+    }
+}
+
+/// Reads pattern table memory that isn't banked, mirrored through $0000-$1FFF if smaller.
+fn chr_read(chr: &[u8], addr: u16) -> u8 {
+    chr[(addr & 0x1FFF) as usize % chr.len()]
+}
+
+/// Writes pattern table memory that isn't banked; writes to CHR-ROM are dropped.
+fn chr_write(chr: &mut [u8], chr_is_ram: bool, addr: u16, data: u8) {
+    if chr_is_ram {
+        let len = chr.len();
+        chr[(addr & 0x1FFF) as usize % len] = data;
+    }
+}
+
+/// Index into PRG-RAM for an address in $6000-$7FFF. Smaller RAMs are mirrored through the
+/// window; boards without any leave it open.
+fn prg_ram_index(prg_ram: &[u8], addr: u16) -> Option<usize> {
+    if prg_ram.is_empty() {
+        None
+    } else {
+        Some((addr - 0x6000) as usize % prg_ram.len())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    /// `count` banks of `size` bytes, each filled with its own number.
+    pub fn numbered_banks(count: u8, size: usize) -> Vec<u8> {
+        (0..count).flat_map(|bank| vec![bank; size]).collect()
+    }
+
+    #[test]
+    fn unsupported_mapper() {
+        let mut rom = test_rom(vec![0; 0x4000]);
+        rom.mapper = 5;
+
+        assert_eq!(for_rom(rom).unwrap_err(), RomError::UnsupportedMapper(5));
+    }
+
+    #[test]
+    fn chr_ram_when_there_is_no_chr_rom() {
+        let mut rom = test_rom(vec![0; 0x4000]);
+        assert_eq!(chr(&rom), (vec![2; 0x2000], false));
+
+        rom.chr_rom = vec![];
+        rom.chr_ram_size = 0;
+        assert_eq!(chr(&rom), (vec![0; 0x2000], true));
+    }
+
+    #[test]
+    fn unbanked_chr() {
+        let mut chr_rom = vec![2; 0x2000];
+        chr_write(&mut chr_rom, false, 0x0010, 0x00);
+        assert_eq!(chr_read(&chr_rom, 0x0010), 0x02);
+
+        let mut chr_ram = vec![0; 0x2000];
+        chr_write(&mut chr_ram, true, 0x1FFF, 0x33);
+        assert_eq!(chr_read(&chr_ram, 0x1FFF), 0x33);
+
+        // a smaller CHR-RAM repeats through the pattern tables
+        let mut chr_ram = vec![0; 0x800];
+        chr_write(&mut chr_ram, true, 0x0842, 0x24);
+        assert_eq!(chr_read(&chr_ram, 0x0042), 0x24);
+        assert_eq!(chr_read(&chr_ram, 0x1842), 0x24);
+    }
+}
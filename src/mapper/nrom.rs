@@ -0,0 +1,140 @@
+use super::Mapper;
+use crate::cartridge::{Mirroring, Rom};
+
+/// Mapper 0: no bank switching. NROM-128 has 16KB of PRG-ROM mirrored into $8000-$BFFF and
+/// $C000-$FFFF, NROM-256 fills both with 32KB. Family Basic adds PRG-RAM at $6000.
+#[derive(Debug)]
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = super::chr(&rom);
+        Nrom {
+            prg_ram: super::prg_ram(&rom),
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            mirroring: rom.screen_mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => super::prg_ram_index(&self.prg_ram, addr).map(|i| self.prg_ram[i]),
+            0x8000..=0xFFFF => Some(self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            if let Some(i) = super::prg_ram_index(&self.prg_ram, addr) {
+                self.prg_ram[i] = data;
+            }
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        super::chr_read(&self.chr, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        super::chr_write(&mut self.chr, self.chr_is_ram, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn nrom_128_is_mirrored() {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0] = 0xAA;
+        prg_rom[0x3FFF] = 0xBB;
+        let mut nrom = Nrom::new(test_rom(prg_rom));
+
+        assert_eq!(nrom.cpu_read(0x8000), Some(0xAA));
+        assert_eq!(nrom.cpu_read(0xC000), Some(0xAA));
+        assert_eq!(nrom.cpu_read(0xBFFF), Some(0xBB));
+        assert_eq!(nrom.cpu_read(0xFFFF), Some(0xBB));
+
+        nrom.cpu_write(0x8000, 0x00);
+        assert_eq!(nrom.cpu_read(0x8000), Some(0xAA));
+    }
+
+    #[test]
+    fn nrom_256_is_not_mirrored() {
+        let mut prg_rom = vec![0; 0x8000];
+        prg_rom[0] = 0xAA;
+        prg_rom[0x4000] = 0xBB;
+        let mut nrom = Nrom::new(test_rom(prg_rom));
+
+        assert_eq!(nrom.cpu_read(0x8000), Some(0xAA));
+        assert_eq!(nrom.cpu_read(0xC000), Some(0xBB));
+    }
+
+    #[test]
+    fn prg_ram() {
+        let mut rom = test_rom(vec![0; 0x4000]);
+        rom.prg_ram_size = 0x800;
+        let mut nrom = Nrom::new(rom);
+        nrom.cpu_write(0x6001, 0x42);
+
+        assert_eq!(nrom.cpu_read(0x6801), Some(0x42));
+        assert_eq!(nrom.cpu_read(0x7801), Some(0x42));
+        assert_eq!(nrom.cpu_read(0x5000), None);
+
+        let mut rom = test_rom(vec![0; 0x4000]);
+        rom.prg_ram_size = 0;
+        let mut nrom = Nrom::new(rom);
+        nrom.cpu_write(0x6000, 0x42);
+        assert_eq!(nrom.cpu_read(0x6000), None);
+    }
+
+    #[test]
+    fn trainer_is_loaded_at_7000() {
+        let mut rom = test_rom(vec![0; 0x4000]);
+        rom.trainer = Some(vec![0x11; 512]);
+        let mut nrom = Nrom::new(rom);
+
+        assert_eq!(nrom.cpu_read(0x6FFF), Some(0x00));
+        assert_eq!(nrom.cpu_read(0x7000), Some(0x11));
+        assert_eq!(nrom.cpu_read(0x71FF), Some(0x11));
+        assert_eq!(nrom.cpu_read(0x7200), Some(0x00));
+    }
+
+    #[test]
+    fn chr_rom_is_read_only() {
+        let mut rom = test_rom(vec![0; 0x4000]);
+        rom.screen_mirroring = Mirroring::Vertical;
+        let mut nrom = Nrom::new(rom);
+        nrom.ppu_write(0x0010, 0x00);
+
+        assert_eq!(nrom.ppu_read(0x0010), 0x02);
+        assert_eq!(nrom.mirroring(), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn chr_ram_is_writable() {
+        let mut rom = test_rom(vec![0; 0x4000]);
+        rom.chr_rom = vec![];
+        let mut nrom = Nrom::new(rom);
+        nrom.ppu_write(0x1FFF, 0x33);
+
+        assert_eq!(nrom.ppu_read(0x1FFF), 0x33);
+    }
+}
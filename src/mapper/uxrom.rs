@@ -0,0 +1,138 @@
+use super::Mapper;
+use crate::cartridge::{Mirroring, Rom};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+/// Mapper 2: UNROM/UOROM. Writes to $8000-$FFFF pick the 16KB PRG bank at $8000-$BFFF; the
+/// last bank is fixed at $C000-$FFFF. CHR is 8KB of unbanked RAM (or ROM on a few boards).
+///
+/// The ROM drives the data bus while the CPU writes to it, so the latch sees the written
+/// value ANDed with the ROM byte at that address. NES 2.0 submapper 1 marks boards that
+/// avoid the conflict.
+#[derive(Debug)]
+pub struct UxRom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    prg_bank: usize,
+}
+
+impl UxRom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = super::chr(&rom);
+        UxRom {
+            bus_conflicts: rom.submapper != 1,
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            mirroring: rom.screen_mirroring,
+            prg_bank: 0,
+        }
+    }
+
+    fn bank_count(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_SIZE).max(1)
+    }
+
+    fn prg_rom_index(&self, addr: u16) -> usize {
+        let bank = if addr < 0xC000 {
+            self.prg_bank
+        } else {
+            self.bank_count() - 1
+        };
+        (bank * PRG_BANK_SIZE + (addr as usize & (PRG_BANK_SIZE - 1))) % self.prg_rom.len()
+    }
+}
+
+impl Mapper for UxRom {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_index(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        let data = if self.bus_conflicts {
+            data & self.prg_rom[self.prg_rom_index(addr)]
+        } else {
+            data
+        };
+        self.prg_bank = data as usize % self.bank_count();
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        super::chr_read(&self.chr, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        super::chr_write(&mut self.chr, self.chr_is_ram, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    /// 128KB of PRG-ROM, each bank filled with its own number except for a $FF at the start.
+    fn uxrom(submapper: u8) -> UxRom {
+        let prg_rom = (0..8)
+            .flat_map(|bank| {
+                let mut bank = vec![bank; PRG_BANK_SIZE];
+                bank[0] = 0xFF;
+                bank
+            })
+            .collect();
+        let mut rom = test_rom(prg_rom);
+        rom.submapper = submapper;
+        UxRom::new(rom)
+    }
+
+    #[test]
+    fn switches_the_low_bank() {
+        let mut uxrom = uxrom(0);
+        assert_eq!(uxrom.cpu_read(0x8001), Some(0));
+        assert_eq!(uxrom.cpu_read(0xC001), Some(7));
+
+        uxrom.cpu_write(0x8000, 3);
+        assert_eq!(uxrom.cpu_read(0x8001), Some(3));
+        assert_eq!(uxrom.cpu_read(0xC001), Some(7));
+        assert_eq!(uxrom.cpu_read(0x6000), None);
+    }
+
+    #[test]
+    fn bus_conflicts() {
+        let mut conflicting = uxrom(0);
+        // $C001 holds 7, so 5 & 7 gets through
+        conflicting.cpu_write(0xC001, 0x05);
+        assert_eq!(conflicting.cpu_read(0x8001), Some(5));
+
+        // $8001 now holds 5: 2 & 5 is 0
+        conflicting.cpu_write(0x8001, 0x02);
+        assert_eq!(conflicting.cpu_read(0x8001), Some(0));
+
+        let mut clean = uxrom(1);
+        clean.cpu_write(0x8001, 0x02);
+        assert_eq!(clean.cpu_read(0x8001), Some(2));
+    }
+
+    #[test]
+    fn chr_ram() {
+        let mut rom = test_rom(vec![0; 2 * PRG_BANK_SIZE]);
+        rom.chr_rom = vec![];
+        let mut uxrom = UxRom::new(rom);
+        uxrom.ppu_write(0x1234, 0x56);
+
+        assert_eq!(uxrom.ppu_read(0x1234), 0x56);
+    }
+}
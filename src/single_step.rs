@@ -0,0 +1,469 @@
+//! A runner for the ProcessorTests/SingleStepTests 6502 vectors
+//! (https://github.com/SingleStepTests/65x02), one JSON file per opcode with 10,000 cases each:
+//!
+//! ```json
+//! {
+//!     "name": "b1 28 b5",
+//!     "initial": { "pc": 59082, "s": 39, "a": 57, "x": 33, "y": 174, "p": 96,
+//!                  "ram": [ [59082, 177], [59083, 40], ... ] },
+//!     "final":   { ... },
+//!     "cycles":  [ [59082, 177, "read"], [59083, 40, "read"], ... ]
+//! }
+//! ```
+//!
+//! Each case loads `initial` into the registers and a flat 64K RAM, executes one instruction
+//! and compares registers, the listed RAM cells, the cycle count and every bus access.
+//!
+//! The core doesn't make the 6502's dummy reads and writes yet, so bus accesses are only
+//! checked for the opcodes that have none: immediate, zero page and absolute (unindexed)
+//! addressing, and JMP. That leaves most of the table, including every implied, stack,
+//! branch and indexed opcode, checked on final state and cycle count alone.
+
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::bus::{Mem, Ram};
+use crate::cpu::{CpuConfig, CPU};
+use crate::flags::CpuFlags;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub access: Access,
+}
+
+impl fmt::Display for BusAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.access {
+            Access::Read => "read",
+            Access::Write => "write",
+        };
+        write!(f, "{} ${:04X} = {:02X}", access, self.addr, self.value)
+    }
+}
+
+/// Plain RAM that logs every access, in order.
+pub struct RecordingBus {
+    ram: Ram,
+    pub log: Vec<BusAccess>,
+}
+
+impl RecordingBus {
+    pub fn new() -> Self {
+        RecordingBus {
+            ram: Ram::new(),
+            log: Vec::new(),
+        }
+    }
+}
+
+impl Mem for RecordingBus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        let value = self.ram.mem_read(addr);
+        self.log.push(BusAccess {
+            addr,
+            value,
+            access: Access::Read,
+        });
+        value
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.ram.mem_write(addr, data);
+        self.log.push(BusAccess {
+            addr,
+            value: data,
+            access: Access::Write,
+        });
+    }
+
+    fn mem_peek(&self, addr: u16) -> u8 {
+        self.ram.mem_peek(addr)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub pc: u16,
+    pub s: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub ram: Vec<(u16, u8)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    pub initial: State,
+    pub expected: State,
+    pub cycles: Vec<BusAccess>,
+}
+
+/// What differed between a case and the CPU. `state` covers registers, RAM and the cycle
+/// count; `bus` covers the individual bus accesses, which include the dummy reads and writes
+/// a real 6502 does on most cycles it isn't otherwise using the bus.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub state: Vec<String>,
+    pub bus: Vec<String>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.state.is_empty() && self.bus.is_empty()
+    }
+}
+
+fn field<'a>(value: &'a Value, key: &str) -> Result<&'a Value, String> {
+    value
+        .get(key)
+        .ok_or_else(|| format!("missing field `{}`", key))
+}
+
+fn number(value: &Value, key: &str) -> Result<u64, String> {
+    field(value, key)?
+        .as_u64()
+        .ok_or_else(|| format!("`{}` is not a number", key))
+}
+
+fn byte(value: &Value) -> Result<u8, String> {
+    value
+        .as_u64()
+        .filter(|v| *v <= 0xFF)
+        .map(|v| v as u8)
+        .ok_or_else(|| format!("{} is not a byte", value))
+}
+
+fn address(value: &Value) -> Result<u16, String> {
+    value
+        .as_u64()
+        .filter(|v| *v <= 0xFFFF)
+        .map(|v| v as u16)
+        .ok_or_else(|| format!("{} is not an address", value))
+}
+
+impl State {
+    fn from_json(value: &Value) -> Result<Self, String> {
+        let ram = field(value, "ram")?
+            .as_array()
+            .ok_or("`ram` is not an array")?
+            .iter()
+            .map(|cell| match cell.as_array().map(Vec::as_slice) {
+                Some([addr, data]) => Ok((address(addr)?, byte(data)?)),
+                _ => Err(format!("bad ram cell {}", cell)),
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(State {
+            pc: number(value, "pc")? as u16,
+            s: number(value, "s")? as u8,
+            a: number(value, "a")? as u8,
+            x: number(value, "x")? as u8,
+            y: number(value, "y")? as u8,
+            p: number(value, "p")? as u8,
+            ram,
+        })
+    }
+}
+
+impl TestCase {
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        let cycles = field(value, "cycles")?
+            .as_array()
+            .ok_or("`cycles` is not an array")?
+            .iter()
+            .map(|cycle| match cycle.as_array().map(Vec::as_slice) {
+                Some([addr, data, Value::String(access)]) => Ok(BusAccess {
+                    addr: address(addr)?,
+                    value: byte(data)?,
+                    access: match access.as_str() {
+                        "read" => Access::Read,
+                        "write" => Access::Write,
+                        _ => return Err(format!("bad access `{}`", access)),
+                    },
+                }),
+                _ => Err(format!("bad cycle {}", cycle)),
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(TestCase {
+            name: field(value, "name")?
+                .as_str()
+                .ok_or("`name` is not a string")?
+                .to_string(),
+            initial: State::from_json(field(value, "initial")?)?,
+            expected: State::from_json(field(value, "final")?)?,
+            cycles,
+        })
+    }
+
+    /// Parses a whole file's worth of cases.
+    pub fn parse_file(json: &str) -> Result<Vec<TestCase>, String> {
+        let value: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        value
+            .as_array()
+            .ok_or("expected an array of test cases")?
+            .iter()
+            .map(TestCase::from_json)
+            .collect()
+    }
+
+    pub fn opcode(&self) -> u8 {
+        let pc = self.initial.pc;
+        self.initial
+            .ram
+            .iter()
+            .find(|(addr, _)| *addr == pc)
+            .map_or(0, |(_, data)| *data)
+    }
+
+    pub fn run(&self) -> Report {
+        let mut cpu = CPU::with_bus(RecordingBus::new());
+        cpu.config = CpuConfig::mos6502();
+        cpu.program_counter = self.initial.pc;
+        cpu.stack_pointer = self.initial.s;
+        cpu.register_a = self.initial.a;
+        cpu.register_x = self.initial.x;
+        cpu.register_y = self.initial.y;
+        cpu.status = CpuFlags::from_bits(self.initial.p);
+        for (addr, data) in &self.initial.ram {
+            cpu.mem_write(*addr, *data);
+        }
+        cpu.bus.log.clear();
+
+        let mut report = Report::default();
+        let cycles = match cpu.step() {
+            Ok(step) => step.cycles,
+            Err(e) => {
+                report.state.push(e.to_string());
+                return report;
+            }
+        };
+        let log = std::mem::take(&mut cpu.bus.log);
+
+        let expected = &self.expected;
+        let registers = [
+            ("pc", expected.pc, cpu.program_counter),
+            ("s", expected.s as u16, cpu.stack_pointer as u16),
+            ("a", expected.a as u16, cpu.register_a as u16),
+            ("x", expected.x as u16, cpu.register_x as u16),
+            ("y", expected.y as u16, cpu.register_y as u16),
+            ("p", expected.p as u16, cpu.status.bits() as u16),
+        ];
+        for (name, expected, actual) in registers {
+            if expected != actual {
+                report.state.push(format!(
+                    "{}: expected {:02X}, got {:02X}",
+                    name, expected, actual
+                ));
+            }
+        }
+
+        for (addr, data) in &expected.ram {
+            let actual = cpu.bus.ram.mem_read(*addr);
+            if actual != *data {
+                report.state.push(format!(
+                    "ram ${:04X}: expected {:02X}, got {:02X}",
+                    addr, data, actual
+                ));
+            }
+        }
+
+        if cycles != self.cycles.len() as u64 {
+            report.state.push(format!(
+                "cycles: expected {}, got {}",
+                self.cycles.len(),
+                cycles
+            ));
+        }
+
+        for i in 0..self.cycles.len().max(log.len()) {
+            match (self.cycles.get(i), log.get(i)) {
+                (Some(expected), Some(actual)) if expected == actual => {}
+                (Some(expected), Some(actual)) => report.bus.push(format!(
+                    "cycle {}: expected {}, got {}",
+                    i, expected, actual
+                )),
+                (Some(expected), None) => report
+                    .bus
+                    .push(format!("cycle {}: expected {}, got nothing", i, expected)),
+                (None, Some(actual)) => report
+                    .bus
+                    .push(format!("cycle {}: unexpected {}", i, actual)),
+                (None, None) => unreachable!(),
+            }
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // the first case of SingleStepTests' a9.json
+    const LDA_IMMEDIATE: &str = r#"[{
+        "name": "a9 e1 5f",
+        "initial": { "pc": 14021, "s": 165, "a": 83, "x": 28, "y": 76, "p": 102,
+                     "ram": [[14021, 169], [14022, 225], [14023, 95]] },
+        "final": { "pc": 14023, "s": 165, "a": 225, "x": 28, "y": 76, "p": 228,
+                   "ram": [[14021, 169], [14022, 225], [14023, 95]] },
+        "cycles": [[14021, 169, "read"], [14022, 225, "read"]]
+    }]"#;
+
+    #[test]
+    fn parses_a_case() {
+        let cases = TestCase::parse_file(LDA_IMMEDIATE).unwrap();
+        assert_eq!(cases.len(), 1);
+
+        let case = cases.first().expect("one case");
+        assert_eq!(case.name, "a9 e1 5f");
+        assert_eq!(case.opcode(), 0xA9);
+        assert_eq!(case.initial.pc, 14021);
+        assert_eq!(case.expected.a, 0xE1);
+        assert_eq!(
+            case.cycles[1],
+            BusAccess {
+                addr: 14022,
+                value: 0xE1,
+                access: Access::Read
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_cases() {
+        assert!(TestCase::parse_file("{}").is_err());
+        assert!(TestCase::parse_file(r#"[{"name": "x"}]"#).is_err());
+    }
+
+    #[test]
+    fn passing_case() {
+        let case = &TestCase::parse_file(LDA_IMMEDIATE).unwrap()[0];
+        let report = case.run();
+        assert!(report.passed(), "{:?}", report);
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        let mut case = TestCase::parse_file(LDA_IMMEDIATE).unwrap().remove(0);
+        case.expected.a = 0x00;
+        case.expected.ram.push((0x0010, 0x01));
+        case.cycles.push(BusAccess {
+            addr: 14023,
+            value: 95,
+            access: Access::Read,
+        });
+
+        let report = case.run();
+        assert_eq!(
+            report.state,
+            vec![
+                "a: expected 00, got E1",
+                "ram $0010: expected 01, got 00",
+                "cycles: expected 3, got 2",
+            ]
+        );
+        assert_eq!(
+            report.bus,
+            vec!["cycle 2: expected read $36C7 = 5F, got nothing"]
+        );
+    }
+
+    /// Opcodes whose dummy bus cycles the core doesn't reproduce yet. Their final state is
+    /// still checked, but their bus activity is not.
+    #[rustfmt::skip]
+    const UNMODELLED_DUMMY_ACCESSES: &[u8] = &[
+        // implied and accumulator: the second cycle re-reads the byte after the opcode
+        0x0a, 0x18, 0x1a, 0x2a, 0x38, 0x3a, 0x4a, 0x58, 0x5a, 0x6a, 0x78, 0x7a, 0x88, 0x8a,
+        0x98, 0x9a, 0xa8, 0xaa, 0xb8, 0xba, 0xc8, 0xca, 0xd8, 0xda, 0xe8, 0xea, 0xf8, 0xfa,
+        // stack and subroutine: dummy reads of the next byte and the stack, and JSR reads the
+        // high byte of its target after pushing
+        0x00, 0x08, 0x20, 0x28, 0x40, 0x48, 0x60, 0x68,
+        // branches: dummy reads of the next opcode when taken, and again on a page cross
+        0x10, 0x30, 0x50, 0x70, 0x90, 0xb0, 0xd0, 0xf0,
+        // zero page indexed: dummy read of the unindexed address
+        0x14, 0x15, 0x16, 0x17, 0x34, 0x35, 0x36, 0x37, 0x54, 0x55, 0x56, 0x57, 0x74, 0x75,
+        0x76, 0x77, 0x94, 0x95, 0x96, 0x97, 0xb4, 0xb5, 0xb6, 0xb7, 0xd4, 0xd5, 0xd6, 0xd7,
+        0xf4, 0xf5, 0xf6, 0xf7,
+        // (indirect,X): dummy read of the unindexed pointer
+        0x01, 0x03, 0x21, 0x23, 0x41, 0x43, 0x61, 0x63, 0x81, 0x83, 0xa1, 0xa3, 0xc1, 0xc3,
+        0xe1, 0xe3,
+        // absolute indexed: dummy read before the high byte is fixed up, on a page cross for
+        // reads and always for writes
+        0x1c, 0x1d, 0x1e, 0x1f, 0x3c, 0x3d, 0x3e, 0x3f, 0x5c, 0x5d, 0x5e, 0x5f, 0x7c, 0x7d,
+        0x7e, 0x7f, 0x9d, 0xbc, 0xbd, 0xdc, 0xdd, 0xde, 0xdf, 0xfc, 0xfd, 0xfe, 0xff,
+        0x19, 0x1b, 0x39, 0x3b, 0x59, 0x5b, 0x79, 0x7b, 0x99, 0xb9, 0xbe, 0xbf, 0xd9, 0xdb,
+        0xf9, 0xfb,
+        // (indirect),Y: the same dummy read as absolute indexed
+        0x11, 0x13, 0x31, 0x33, 0x51, 0x53, 0x71, 0x73, 0x91, 0xb1, 0xb3, 0xd1, 0xd3, 0xf1,
+        0xf3,
+    ];
+
+    /// Runs every vendored opcode file in `test_roms/65x02/6502/v1/`.
+    ///
+    /// Opcodes the CPU doesn't implement (the JAMs and the unstable unofficial opcodes) are
+    /// skipped, and bus activity is only compared for opcodes outside
+    /// [`UNMODELLED_DUMMY_ACCESSES`].
+    #[test]
+    #[ignore = "needs test_roms/65x02/6502/v1/*.json"]
+    fn single_step_tests() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test_roms/65x02/6502/v1");
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .expect("test_roms/65x02/6502/v1")
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        files.sort();
+        assert!(!files.is_empty(), "no test files in {}", dir.display());
+
+        let mut failures = Vec::new();
+        for path in files {
+            let json = std::fs::read_to_string(&path).unwrap();
+            let cases =
+                TestCase::parse_file(&json).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+
+            let opcode = cases
+                .first()
+                .unwrap_or_else(|| panic!("{}: no test cases", path.display()))
+                .opcode();
+            if crate::opcodes::lookup(opcode).is_none() {
+                continue;
+            }
+
+            let check_bus = !UNMODELLED_DUMMY_ACCESSES.contains(&opcode);
+            for case in &cases {
+                let report = case.run();
+                if !report.state.is_empty() {
+                    failures.push(format!("{}: {}", case.name, report.state.join(", ")));
+                }
+                if check_bus && !report.bus.is_empty() {
+                    failures.push(format!("{}: {}", case.name, report.bus.join(", ")));
+                }
+            }
+        }
+
+        assert!(
+            failures.is_empty(),
+            "{} failing cases, first: {}",
+            failures.len(),
+            failures
+                .iter()
+                .take(20)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+}
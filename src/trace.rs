@@ -0,0 +1,291 @@
+#![allow(dead_code)]
+
+use crate::bus::Mem;
+use crate::cpu::{AddressingMode, CPU};
+use crate::opcodes;
+
+const PPU_DOTS_PER_SCANLINE: u64 = 341;
+const PPU_SCANLINES_PER_FRAME: u64 = 262;
+const APU_IO_REGISTERS: u16 = 0x4000;
+const APU_IO_REGISTERS_END: u16 = 0x401F;
+
+/// Formats the instruction at the program counter the way nestest.log does, with the
+/// registers as they are before it executes:
+///
+/// `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7`
+///
+/// There is no PPU yet, so its position is derived from the CPU cycle count (three dots per
+/// cycle), which is what the real PPU shows while rendering is off.
+///
+/// Memory is read with [`Mem::mem_peek`], so tracing leaves the bus and the cartridge as
+/// they were.
+pub fn trace<M: Mem>(cpu: &CPU<M>) -> String {
+    let begin = cpu.program_counter;
+    let code = cpu.mem_peek(begin);
+
+    let (mnemonic, len, mode) = match opcodes::lookup(code) {
+        Some(op) => (op.mnemonic, op.len, op.mode),
+        None => ("???", 1, AddressingMode::NoneAddressing),
+    };
+
+    let mut hex_dump = vec![code];
+    for i in 1..len as u16 {
+        hex_dump.push(cpu.mem_peek(begin.wrapping_add(i)));
+    }
+
+    let operand = match (len, mode) {
+        (1, _) => match code {
+            0x0a | 0x4a | 0x2a | 0x6a => "A".to_string(),
+            _ => String::new(),
+        },
+        // branches
+        (2, AddressingMode::NoneAddressing) => {
+            let target = begin.wrapping_add(2).wrapping_add(hex_dump[1] as i8 as u16);
+            format!("${:04X}", target)
+        }
+        _ => {
+            let (addr, _) = cpu
+                .peek_absolute_address(&mode, begin.wrapping_add(1))
+                .expect("every mode with an operand resolves");
+            let value = match addr {
+                // Nintendulator logs the APU and I/O registers as $FF rather than open bus
+                APU_IO_REGISTERS..=APU_IO_REGISTERS_END => 0xFF,
+                _ => cpu.mem_peek(addr),
+            };
+            format_operand(code, mode, &hex_dump, addr, value, cpu)
+        }
+    };
+
+    let hex_str = hex_dump
+        .iter()
+        .map(|z| format!("{:02X}", z))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let asm_str = format!("{:04X}  {:8} {: >4} {}", begin, hex_str, mnemonic, operand)
+        .trim_end()
+        .to_string();
+
+    let ppu_dots = cpu.cycles * 3;
+    let scanline = (ppu_dots / PPU_DOTS_PER_SCANLINE) % PPU_SCANLINES_PER_FRAME;
+    let dot = ppu_dots % PPU_DOTS_PER_SCANLINE;
+
+    format!(
+        "{:47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:3},{:3} CYC:{}",
+        asm_str,
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status.bits(),
+        cpu.stack_pointer,
+        scanline,
+        dot,
+        cpu.cycles,
+    )
+}
+
+fn format_operand<M: Mem>(
+    code: u8,
+    mode: AddressingMode,
+    hex_dump: &[u8],
+    addr: u16,
+    value: u8,
+    cpu: &CPU<M>,
+) -> String {
+    match mode {
+        AddressingMode::Immediate => format!("#${:02X}", value),
+        AddressingMode::ZeroPage => format!("${:02X} = {:02X}", addr, value),
+        AddressingMode::ZeroPageX => {
+            format!("${:02X},X @ {:02X} = {:02X}", hex_dump[1], addr, value)
+        }
+        AddressingMode::ZeroPageY => {
+            format!("${:02X},Y @ {:02X} = {:02X}", hex_dump[1], addr, value)
+        }
+        AddressingMode::IndirectX => format!(
+            "(${:02X},X) @ {:02X} = {:04X} = {:02X}",
+            hex_dump[1],
+            hex_dump[1].wrapping_add(cpu.register_x),
+            addr,
+            value
+        ),
+        AddressingMode::IndirectY => format!(
+            "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+            hex_dump[1],
+            addr.wrapping_sub(cpu.register_y as u16),
+            addr,
+            value
+        ),
+        // JMP and JSR show the target, not what's stored there
+        AddressingMode::Absolute if code == 0x4c || code == 0x20 => format!("${:04X}", addr),
+        AddressingMode::Absolute => format!("${:04X} = {:02X}", addr, value),
+        AddressingMode::AbsoluteX => format!(
+            "${:02X}{:02X},X @ {:04X} = {:02X}",
+            hex_dump[2], hex_dump[1], addr, value
+        ),
+        AddressingMode::AbsoluteY => format!(
+            "${:02X}{:02X},Y @ {:04X} = {:02X}",
+            hex_dump[2], hex_dump[1], addr, value
+        ),
+        AddressingMode::Indirect => {
+            format!("(${:02X}{:02X}) = {:04X}", hex_dump[2], hex_dump[1], addr)
+        }
+        AddressingMode::NoneAddressing => {
+            unreachable!("implied and relative operands are formatted by the caller")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Rom;
+
+    #[test]
+    fn format_trace() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(100, 0xA2);
+        cpu.mem_write(101, 0x01);
+        cpu.mem_write(102, 0xCA);
+        cpu.mem_write(103, 0x88);
+        cpu.mem_write(104, 0x00);
+        cpu.program_counter = 0x64;
+        cpu.register_a = 1;
+        cpu.register_x = 2;
+        cpu.register_y = 3;
+        cpu.stack_pointer = 0xFD;
+        cpu.status = crate::flags::CpuFlags::from_bits(0x24);
+        cpu.cycles = 7;
+
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| result.push(trace(cpu)))
+            .unwrap();
+
+        assert_eq!(
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD PPU:  0, 21 CYC:7",
+            result[0]
+        );
+        assert_eq!(
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD PPU:  0, 27 CYC:9",
+            result[1]
+        );
+        assert_eq!(
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD PPU:  0, 33 CYC:11",
+            result[2]
+        );
+    }
+
+    #[test]
+    fn format_mem_access() {
+        let mut cpu = CPU::new();
+        // ORA ($33), Y
+        cpu.mem_write(100, 0x11);
+        cpu.mem_write(101, 0x33);
+
+        // data
+        cpu.mem_write(0x33, 0x00);
+        cpu.mem_write(0x34, 0x04);
+
+        // target cell
+        cpu.mem_write(0x400, 0xAA);
+
+        cpu.program_counter = 0x64;
+        cpu.register_y = 0;
+        cpu.status = crate::flags::CpuFlags::from_bits(0x24);
+        cpu.stack_pointer = 0xFD;
+
+        assert_eq!(
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+            trace(&cpu)
+        );
+    }
+
+    #[test]
+    fn format_addressing_modes() {
+        let mut cpu = CPU::new();
+        cpu.register_x = 0x01;
+        cpu.register_y = 0x02;
+        cpu.mem_write(0x0011, 0x5A);
+        cpu.mem_write(0x0301, 0x77);
+        cpu.mem_write_u16(0x0021, 0x0300);
+        cpu.mem_write_u16(0x02FF, 0x1234);
+        cpu.mem_write(0x0200, 0x56);
+
+        let cases: &[(&[u8], &str)] = &[
+            (&[0xB5, 0x10], "LDA $10,X @ 11 = 5A"),
+            (&[0xBD, 0x00, 0x03], "LDA $0300,X @ 0301 = 77"),
+            (&[0xA1, 0x20], "LDA ($20,X) @ 21 = 0300 = 12"),
+            (&[0x4C, 0x34, 0x12], "JMP $1234"),
+            (&[0x6C, 0xFF, 0x02], "JMP ($02FF) = 5634"),
+            (&[0x20, 0x34, 0x12], "JSR $1234"),
+            (&[0x00], "BRK"),
+            (&[0x60], "RTS"),
+            (&[0x0A], "ASL A"),
+            (&[0xAD, 0x15, 0x40], "LDA $4015 = FF"),
+            (&[0xD0, 0xFE], "BNE $8000"),
+            (&[0x04, 0x11], "*NOP $11 = 5A"),
+        ];
+
+        for (program, expected) in cases {
+            for (i, byte) in program.iter().enumerate() {
+                cpu.mem_write(0x8000 + i as u16, *byte);
+            }
+            cpu.program_counter = 0x8000;
+            let line = trace(&cpu);
+            assert_eq!(line[15..47].trim(), *expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn format_subroutine_from_cartridge() {
+        // $C000: JSR $C004; NOP; RTS, booted the same way as the nestest run below
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[..5].copy_from_slice(&[0x20, 0x04, 0xC0, 0xEA, 0x60]);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        let mut cpu = CPU::from_rom(crate::cartridge::test::test_rom(prg_rom)).unwrap();
+
+        let mut result = vec![];
+        for _ in 0..3 {
+            result.push(trace(&cpu));
+            cpu.step().unwrap();
+        }
+
+        assert_eq!(
+            result,
+            [
+                "C000  20 04 C0  JSR $C004                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+                "C004  60        RTS                             A:00 X:00 Y:00 P:24 SP:FB PPU:  0, 39 CYC:13",
+                "C003  EA        NOP                             A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 57 CYC:19",
+            ]
+        );
+    }
+
+    /// Runs nestest.nes from $C000, its automated entry point, and compares every trace line
+    /// with the log from Nintendulator. nestest writes the number of the first failing test to
+    /// $0002 (official opcodes) and $0003 (unofficial opcodes), or leaves 0 if they all pass.
+    ///
+    /// The ROM and log are not redistributable with the crate; drop them into `test_roms/` and
+    /// run `cargo test -- --ignored`.
+    #[test]
+    #[ignore = "needs test_roms/nestest.nes and test_roms/nestest.log"]
+    fn nestest_matches_golden_log() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test_roms");
+        let rom = std::fs::read(dir.join("nestest.nes")).expect("test_roms/nestest.nes");
+        let log = std::fs::read_to_string(dir.join("nestest.log")).expect("test_roms/nestest.log");
+
+        let mut cpu = CPU::from_rom(Rom::new(&rom).unwrap()).unwrap();
+        cpu.program_counter = 0xC000;
+
+        for (line_number, expected) in log.lines().enumerate() {
+            let actual = trace(&cpu);
+            assert_eq!(
+                actual,
+                expected.trim_end(),
+                "nestest.log line {} differs",
+                line_number + 1
+            );
+            cpu.step().unwrap();
+        }
+
+        assert_eq!(cpu.mem_read(0x0002), 0x00, "official opcode test failed");
+        assert_eq!(cpu.mem_read(0x0003), 0x00, "unofficial opcode test failed");
+    }
+}